@@ -1,21 +1,76 @@
 use message_io::network::{Endpoint, ResourceId};
 use serde::{Serialize, Deserialize};
+use crate::profile::Profile;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 
 pub enum SendToSerial {
     AskForPorts(),
     SelectPort(String, usize),
+    // Name, baud rate and data bits, parity, stop bits like "8N1"
+    OpenPort(String, usize, String),
     SendMessage(String),
+    // Written as they are, for payloads that are not text
+    SendBytes(Vec<u8>),
+    // Panel coordinates, 0 up to the profile width and height
+    Touch(u16, u16),
+    // Packed 1 bit frame (see bitmap.rs), sent as "image:" followed by the framed screen.
+    // Not escaped, the firmware reads exactly the packed length of its screen
+    SendImage(Vec<u8>),
+    // Names of the watches on a ws-serial-tcp server
+    AskForDevices(),
+    // Flash offset and firmware image, written through the ROM bootloader (see flash.rs)
+    Flash(u32, Vec<u8>),
+    // Screen, markers, commands and log format of the firmware, see profile.rs
+    SetProfile(Profile),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SendToGui {
     Ports(Vec<String>),
     LogToShow(String),
     ShowPng(Vec<u8>),
+    // Packed 1 bit frame (see bitmap.rs): width, height, bits
+    ShowFrame(u16, u16, Vec<u8>),
+    // Width, height, keyframe, RLE data (see codec.rs). Not keyframes are XOR deltas to the previous frame
+    ShowFrameRle(u16, u16, bool, Vec<u8>),
+    Stats(LinkStats),
+    Devices(Vec<String>),
+    // Written and total bytes of the firmware
+    FlashProgress(usize, usize),
+    FlashDone(Result<(), String>),
+    // The profile the backend uses now
+    Profile(Profile),
+    // Connection of the remote backend to its server was made or lost
+    Connected(bool),
+}
+
+// Network envelope, one server can host several watches
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tagged<T> {
+    pub device: String,
+    pub message: T,
+}
+
+// Device used by clients that don't pick one and by an unnamed --serial
+pub const DEFAULT_DEVICE: &str = "default";
+
+// Serial link quality, rates are over the last report interval, counts since the port was opened
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LinkStats {
+    pub bytes_per_sec: f32,
+    pub frames_per_sec: f32,
+    // From reading the end packet to the decoded screen
+    pub decode_latency_ms: f32,
+    pub wrong_length_frames: u64,
+    // Frames thrown away because their end packet was lost
+    pub resyncs: u64,
 }
 
 pub enum ThreadCom {
     ClientConnected(Endpoint, ResourceId),
+    ClientDisconnected(Endpoint),
+    FromClient(Endpoint, Tagged<SendToSerial>),
+    // Device name and its message
+    FromSerial(String, SendToGui),
 }
\ No newline at end of file
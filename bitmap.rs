@@ -0,0 +1,73 @@
+use image::{imageops, DynamicImage, ImageBuffer, Rgb, Rgba, RgbaImage};
+
+// 1 bit per pixel, row major, most significant bit first, 1 is white.
+// The Watchy panel is the default, other sizes come from the profile.
+pub const WIDTH: usize = 200;
+pub const HEIGHT: usize = 200;
+
+pub fn packed_len(width: usize, height: usize) -> usize {
+    (width * height).div_ceil(8)
+}
+
+// Flips the pixel order inside every byte, for firmwares that send the least significant bit first
+pub fn reverse_bits(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        *byte = byte.reverse_bits();
+    }
+}
+
+// The gui unpacks frames and packs uploads, the server does neither
+#[allow(dead_code)]
+pub fn unpack(screen: &[u8], width: usize, height: usize) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let mut img = ImageBuffer::<Rgb<u8>, _>::new(width as u32, height as u32);
+    for y in 0..height {
+        for x in 0..width {
+            let index = (y * width + x) / 8;
+            let bit_offset = 7 - ((y * width + x) % 8);
+
+            let i_option = screen.get(index);
+            if let Some(i) = i_option {
+                let bit = (i >> bit_offset) & 1;
+
+                let color = if bit == 0 {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                };
+
+                img.put_pixel(x as u32, y as u32, color);
+            } else {
+                //error!("Error creating image, pixels missing");
+                img.put_pixel(x as u32, y as u32, Rgb([255, 0, 0]));
+            }
+        }
+    }
+    img
+}
+
+// Scales the image to fit the panel (centered on white) and converts it to the packed format
+#[allow(dead_code)]
+pub fn pack(img: &DynamicImage, width: usize, height: usize, dither: bool) -> Vec<u8> {
+    let scaled = img.resize(width as u32, height as u32, imageops::FilterType::Triangle);
+    // Transparent parts end up white, like the panel background
+    let mut canvas = RgbaImage::from_pixel(width as u32, height as u32, Rgba([255, 255, 255, 255]));
+    imageops::overlay(
+        &mut canvas,
+        &scaled.to_rgba8(),
+        ((width as u32 - scaled.width()) / 2) as i64,
+        ((height as u32 - scaled.height()) / 2) as i64,
+    );
+    let mut gray = DynamicImage::ImageRgba8(canvas).to_luma8();
+    if dither {
+        imageops::dither(&mut gray, &imageops::BiLevel);
+    }
+
+    let mut packed = vec![0u8; packed_len(width, height)];
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        if pixel[0] > 127 {
+            let position = y as usize * width + x as usize;
+            packed[position / 8] |= 1 << (7 - (position % 8));
+        }
+    }
+    packed
+}
@@ -0,0 +1,75 @@
+// Frame compression shared by the server, the gui and the watch link
+//
+// RLE is a list of (count, value) byte pairs, count is 1..=255.
+// Deltas are the previous frame XORed with the new one, so unchanged pixels become
+// long runs of zeros.
+
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut iter = data.iter().peekable();
+    while let Some(&value) = iter.next() {
+        let mut count: u8 = 1;
+        while count < u8::MAX && iter.peek() == Some(&&value) {
+            iter.next();
+            count += 1;
+        }
+        out.push(count);
+        out.push(value);
+    }
+    out
+}
+
+pub fn rle_decode(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len() * 4);
+    for pair in data.chunks_exact(2) {
+        if pair[0] == 0 {
+            return None;
+        }
+        out.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+    }
+    Some(out)
+}
+
+pub fn xor(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    previous.iter().zip(current).map(|(a, b)| a ^ b).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_round_trip() {
+        assert!(rle_encode(&[]).is_empty());
+        assert_eq!(rle_decode(&[]), Some(vec![]));
+        let data = [1, 1, 2, 3, 3, 3];
+        assert_eq!(rle_encode(&data), [2, 1, 1, 2, 3, 3]);
+        assert_eq!(rle_decode(&rle_encode(&data)), Some(data.to_vec()));
+    }
+
+    #[test]
+    fn splits_long_runs() {
+        let data = vec![0; 600];
+        let encoded = rle_encode(&data);
+        assert_eq!(encoded, [255, 0, 255, 0, 90, 0]);
+        assert_eq!(rle_decode(&encoded), Some(data));
+    }
+
+    #[test]
+    fn refuses_bad_rle() {
+        assert_eq!(rle_decode(&[1]), None);
+        assert_eq!(rle_decode(&[0, 5]), None);
+    }
+
+    #[test]
+    fn xor_delta_restores_frame() {
+        let previous = [0xFF, 0x00, 0xAA];
+        let current = [0xFF, 0x0F, 0x55];
+        let delta = xor(&previous, &current);
+        assert_eq!(delta, [0x00, 0x0F, 0xFF]);
+        assert_eq!(xor(&previous, &delta), current);
+    }
+}
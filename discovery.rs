@@ -0,0 +1,235 @@
+// Finds ws-serial-tcp servers on the local network with mDNS/DNS-SD. Every server
+// registers one instance of SERVICE_TYPE, its TXT record lists the watches:
+// devices=default,desk
+// Loopback is enabled on both sides, so a server and a gui on one machine (or a
+// machine without a network) still find each other.
+
+use log::{debug, error, info};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+
+pub const SERVICE_TYPE: &str = "_watchy-scom._tcp.local.";
+const DEVICES_KEY: &str = "devices";
+// Of one TXT entry, key and = included
+const MAX_TXT_ENTRY: usize = 255;
+pub const MAX_DEVICE_NAME: usize = 32;
+
+// Browsing is for the gui, the server only advertises
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+    pub name: String,
+    // host:port for the remote backend
+    pub address: String,
+    pub devices: Vec<String>,
+}
+
+// Host name of this machine, for the instance name
+pub fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|x| x.trim().to_string())
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "ws-serial-tcp".to_string())
+}
+
+// Names are listed in the TXT record, a comma would split one in two
+pub fn check_device_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_DEVICE_NAME {
+        return Err(format!(
+            "Device name {:?} must have 1 to {} bytes",
+            name, MAX_DEVICE_NAME
+        ));
+    }
+    if name.contains(',') {
+        return Err(format!("Device name {:?} must not contain a comma", name));
+    }
+    Ok(())
+}
+
+fn new_daemon() -> Result<ServiceDaemon, String> {
+    let daemon = ServiceDaemon::new().map_err(|x| format!("Failed to start mDNS: {}", x))?;
+    if let Err(x) = daemon.enable_interface(IfKind::LoopbackV4) {
+        debug!("Failed to enable mDNS on loopback: {}", x);
+    }
+    Ok(daemon)
+}
+
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    instance: String,
+    host: String,
+    port: u16,
+}
+
+impl Advertiser {
+    pub fn new(name: &str, port: u16, devices: &[String]) -> Result<Self, String> {
+        // Several servers can run on one machine, the port tells them apart
+        let instance = format!("{}-{}", name, port);
+        let label: String = name
+            .chars()
+            .map(|x| if x.is_ascii_alphanumeric() { x } else { '-' })
+            .collect();
+        let advertiser = Self {
+            daemon: new_daemon()?,
+            instance,
+            host: format!("{}.local.", label),
+            port,
+        };
+        advertiser.set_devices(devices)?;
+        info!("Advertising {} on the local network", advertiser.instance);
+        Ok(advertiser)
+    }
+
+    // Registering the same instance again replaces its TXT record
+    pub fn set_devices(&self, devices: &[String]) -> Result<(), String> {
+        // Devices that don't fit in the entry aren't advertised, clients still
+        // get all of them with AskForDevices
+        let mut list = String::new();
+        for device in devices {
+            let sep = usize::from(!list.is_empty());
+            if DEVICES_KEY.len() + 1 + list.len() + sep + device.len() > MAX_TXT_ENTRY {
+                debug!("Not advertising device {}, the TXT record is full", device);
+                continue;
+            }
+            if sep == 1 {
+                list.push(',');
+            }
+            list.push_str(device);
+        }
+        let properties = [(DEVICES_KEY, list)];
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.instance,
+            &self.host,
+            "",
+            self.port,
+            &properties[..],
+        )
+        .map_err(|x| format!("Failed to describe the mDNS service: {}", x))?
+        .enable_addr_auto();
+        self.daemon
+            .register(service)
+            .map_err(|x| format!("Failed to register the mDNS service: {}", x))
+    }
+}
+
+// Loopback only when there is nothing else, other machines can't reach it
+#[allow(dead_code)]
+fn best_address(addresses: impl Iterator<Item = IpAddr>, port: u16) -> Option<String> {
+    addresses
+        .min_by_key(|x| (x.is_loopback(), x.is_ipv6()))
+        .map(|x| SocketAddr::new(x, port).to_string())
+}
+
+// Calls on_change with every server found so far, sorted by name, whenever one
+// appears, changes or goes away. Dropping the daemon doesn't stop it, it lives
+// as long as the program.
+#[allow(dead_code)]
+pub fn browse(on_change: impl Fn(Vec<Server>) + Send + 'static) -> Result<ServiceDaemon, String> {
+    let daemon = new_daemon()?;
+    let events = daemon
+        .browse(SERVICE_TYPE)
+        .map_err(|x| format!("Failed to browse mDNS: {}", x))?;
+    thread::spawn(move || {
+        let mut servers: HashMap<String, Server> = HashMap::new();
+        // Every answer may carry only some of the addresses
+        let mut addresses: HashMap<String, HashSet<IpAddr>> = HashMap::new();
+        while let Ok(event) = events.recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    let known = addresses
+                        .entry(info.get_fullname().to_string())
+                        .or_default();
+                    known.extend(info.get_addresses());
+                    let Some(address) = best_address(known.iter().copied(), info.get_port()) else {
+                        continue;
+                    };
+                    let devices = info
+                        .get_property_val_str(DEVICES_KEY)
+                        .unwrap_or_default()
+                        .split(',')
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.to_string())
+                        .collect();
+                    let name = info
+                        .get_fullname()
+                        .trim_end_matches(SERVICE_TYPE)
+                        .trim_end_matches('.')
+                        .to_string();
+                    let server = Server {
+                        name,
+                        address,
+                        devices,
+                    };
+                    if servers.get(info.get_fullname()) == Some(&server) {
+                        continue;
+                    }
+                    debug!("Found server {:?}", server);
+                    servers.insert(info.get_fullname().to_string(), server);
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    addresses.remove(&fullname);
+                    if servers.remove(&fullname).is_none() {
+                        continue;
+                    }
+                    debug!("Server {} is gone", fullname);
+                }
+                _ => continue,
+            }
+            let mut list: Vec<Server> = servers.values().cloned().collect();
+            list.sort_by(|a, b| a.name.cmp(&b.name));
+            on_change(list);
+        }
+        error!("mDNS browsing stopped");
+    });
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn checks_device_names() {
+        assert!(check_device_name("desk").is_ok());
+        assert!(check_device_name(&"a".repeat(MAX_DEVICE_NAME)).is_ok());
+        assert!(check_device_name("").is_err());
+        assert!(check_device_name("a,b").is_err());
+        assert!(check_device_name(&"a".repeat(MAX_DEVICE_NAME + 1)).is_err());
+    }
+
+    #[test]
+    fn browse_finds_advertiser() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let name = format!("discovery-test-{}", std::process::id());
+        let devices = vec!["default".to_string(), "desk".to_string()];
+        let _advertiser = Advertiser::new(&name, port, &devices).unwrap();
+        let (tx, rx) = channel();
+        let _daemon = browse(move |x| {
+            let _ = tx.send(x);
+        })
+        .unwrap();
+        let instance = format!("{}-{}", name, port);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let servers = rx.recv_timeout(left).expect("Advertiser not found");
+            if let Some(server) = servers.iter().find(|x| x.name == instance) {
+                assert_eq!(server.devices, devices);
+                assert!(server.address.ends_with(&format!(":{}", port)));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,647 @@
+// Writes firmware with the ESP32 ROM loader protocol, the same one esptool uses
+// without its stub. Commands and responses are SLIP framed:
+// - command: 0x00, opcode, data length (u16), checksum (u32), data
+// - response: 0x01, opcode, data length (u16), value (u32), data ending with the status
+// Numbers are little endian. The written flash is checked with its MD5 at the end.
+
+use log::{debug, error, info};
+use serialport::{ClearBuffer, SerialPort};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FLASH_BEGIN: u8 = 0x02;
+const FLASH_DATA: u8 = 0x03;
+const FLASH_END: u8 = 0x04;
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0A;
+const SPI_SET_PARAMS: u8 = 0x0B;
+const SPI_ATTACH: u8 = 0x0D;
+const CHANGE_BAUDRATE: u8 = 0x0F;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+const ROM_BAUD_RATE: u32 = 115200;
+const BLOCK_SIZE: usize = 0x400;
+const CHECKSUM_SEED: u8 = 0xEF;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+// Flash is erased during FLASH_BEGIN
+const ERASE_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
+// ESP32 ROMs end every response with status, error and 2 reserved bytes
+const STATUS_LEN: usize = 4;
+
+const CHIP_DETECT_MAGIC_REG: u32 = 0x40001000;
+const ESP32_MAGIC: u32 = 0x00f01d83;
+
+// Where the app partition starts in the default partition tables, the gui
+// parses the offset and the server only flashes
+#[allow(dead_code)]
+pub const DEFAULT_OFFSET: u32 = 0x10000;
+
+#[allow(dead_code)]
+pub fn parse_offset(offset: &str) -> Option<u32> {
+    let offset = offset.trim();
+    match offset
+        .strip_prefix("0x")
+        .or_else(|| offset.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => offset.parse().ok(),
+    }
+}
+
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(packet.len() + 8);
+    out.push(0xC0);
+    for &byte in packet {
+        match byte {
+            0xC0 => out.extend([0xDB, 0xDC]),
+            0xDB => out.extend([0xDB, 0xDD]),
+            x => out.push(x),
+        }
+    }
+    out.push(0xC0);
+    out
+}
+
+fn slip_decode(frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut escaped = false;
+    for &byte in frame {
+        match (escaped, byte) {
+            (false, 0xDB) => escaped = true,
+            (true, 0xDC) => {
+                out.push(0xC0);
+                escaped = false;
+            }
+            (true, 0xDD) => {
+                out.push(0xDB);
+                escaped = false;
+            }
+            (_, x) => {
+                out.push(x);
+                escaped = false;
+            }
+        }
+    }
+    out
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+// RFC 1321, what SPI_FLASH_MD5 answers with
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constant = |i: usize| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32;
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(constant(i))
+                .wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (x, y) in state.iter_mut().zip([a, b, c, d]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    let mut digest = [0; 16];
+    for (out, x) in digest.chunks_mut(4).zip(state) {
+        out.copy_from_slice(&x.to_le_bytes());
+    }
+    digest
+}
+
+struct Loader<'a> {
+    port: &'a mut dyn SerialPort,
+    // Port data as it arrives, None on timeout
+    read: &'a mut dyn FnMut(Duration) -> Option<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Loader<'_> {
+    fn read_frame(&mut self, deadline: Instant) -> Result<Vec<u8>, String> {
+        loop {
+            match self.buf.iter().position(|x| *x == 0xC0) {
+                Some(start) => {
+                    if let Some(len) = self.buf[start + 1..].iter().position(|x| *x == 0xC0) {
+                        let frame = slip_decode(&self.buf[start + 1..start + 1 + len]);
+                        self.buf.drain(..start + 2 + len);
+                        if !frame.is_empty() {
+                            return Ok(frame);
+                        }
+                        continue;
+                    }
+                }
+                // Boot messages before the first frame
+                None => self.buf.clear(),
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Err("Timed out waiting for the bootloader".to_string());
+            };
+            match (self.read)(left) {
+                Some(data) => self.buf.extend(data),
+                None => return Err("Timed out waiting for the bootloader".to_string()),
+            }
+        }
+    }
+
+    fn command(
+        &mut self,
+        opcode: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> Result<u32, String> {
+        self.command_data(opcode, data, checksum, timeout)
+            .map(|(value, _)| value)
+    }
+
+    // The value and the response data before the status
+    fn command_data(
+        &mut self,
+        opcode: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> Result<(u32, Vec<u8>), String> {
+        let mut packet = vec![0x00, opcode];
+        packet.extend((data.len() as u16).to_le_bytes());
+        packet.extend(checksum.to_le_bytes());
+        packet.extend(data);
+        self.port
+            .write_all(&slip_encode(&packet))
+            .and_then(|_| self.port.flush())
+            .map_err(|x| format!("Failed to write command {:#04x}: {}", opcode, x))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let frame = self.read_frame(deadline)?;
+            // Late answers to earlier commands, like the extra SYNC replies
+            if frame.len() < 8 + STATUS_LEN || frame[0] != 0x01 || frame[1] != opcode {
+                continue;
+            }
+            let value = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+            let (data, status) = frame[8..].split_at(frame.len() - 8 - STATUS_LEN);
+            if status[0] != 0 {
+                return Err(format!(
+                    "Command {:#04x} failed with error {:#04x}",
+                    opcode, status[1]
+                ));
+            }
+            return Ok((value, data.to_vec()));
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        let mut data = vec![0x07, 0x07, 0x12, 0x20];
+        data.extend([0x55; 32]);
+        for attempt in 1..=10 {
+            match self.command(SYNC, &data, 0, Duration::from_millis(100)) {
+                Ok(_) => return Ok(()),
+                Err(x) => debug!("Sync attempt {} failed: {}", attempt, x),
+            }
+        }
+        Err("Failed to sync with the bootloader, is the watch in download mode?".to_string())
+    }
+}
+
+fn set_lines(port: &mut dyn SerialPort, dtr: bool, rts: bool) {
+    if port.write_data_terminal_ready(dtr).is_err() || port.write_request_to_send(rts).is_err() {
+        error!("Failed to set DTR and RTS, reset the watch by hand");
+    }
+}
+
+// The classic auto reset circuit: RTS drives EN, DTR drives IO0
+fn enter_bootloader(port: &mut dyn SerialPort) {
+    set_lines(port, false, true);
+    thread::sleep(Duration::from_millis(100));
+    set_lines(port, true, false);
+    thread::sleep(Duration::from_millis(50));
+    set_lines(port, false, false);
+}
+
+fn hard_reset(port: &mut dyn SerialPort) {
+    set_lines(port, false, true);
+    thread::sleep(Duration::from_millis(100));
+    set_lines(port, false, false);
+}
+
+fn write_image(
+    loader: &mut Loader,
+    baud_rate: u32,
+    offset: u32,
+    image: &[u8],
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), String> {
+    loader.sync()?;
+    let magic = loader.command(
+        READ_REG,
+        &words(&[CHIP_DETECT_MAGIC_REG]),
+        0,
+        COMMAND_TIMEOUT,
+    )?;
+    // Newer chips take an extra encryption flag in FLASH_BEGIN
+    let esp32 = magic == ESP32_MAGIC;
+    info!("Bootloader synced, chip magic {:#010x}", magic);
+
+    if baud_rate != ROM_BAUD_RATE {
+        match loader.command(CHANGE_BAUDRATE, &words(&[baud_rate, 0]), 0, COMMAND_TIMEOUT) {
+            Ok(_) => {
+                if loader.port.set_baud_rate(baud_rate).is_err() {
+                    return Err("Failed to change the port baud rate".to_string());
+                }
+                thread::sleep(Duration::from_millis(50));
+                let _ = loader.port.clear(ClearBuffer::Input);
+                loader.buf.clear();
+            }
+            Err(x) => error!("Staying at {} baud: {}", ROM_BAUD_RATE, x),
+        }
+    }
+
+    loader.command(SPI_ATTACH, &words(&[0, 0]), 0, COMMAND_TIMEOUT)?;
+    // The size only bounds writes, 16MB covers every module
+    loader.command(
+        SPI_SET_PARAMS,
+        &words(&[0, 16 * 1024 * 1024, 64 * 1024, 4 * 1024, 256, 0xFFFF]),
+        0,
+        COMMAND_TIMEOUT,
+    )?;
+
+    let blocks = image.len().div_ceil(BLOCK_SIZE) as u32;
+    let mut begin = vec![image.len() as u32, blocks, BLOCK_SIZE as u32, offset];
+    if !esp32 {
+        begin.push(0);
+    }
+    let erase_timeout =
+        COMMAND_TIMEOUT.max(ERASE_TIMEOUT_PER_MB.mul_f32(image.len() as f32 / 1_000_000.0));
+    loader.command(FLASH_BEGIN, &words(&begin), 0, erase_timeout)?;
+
+    for (seq, chunk) in image.chunks(BLOCK_SIZE).enumerate() {
+        let mut block = chunk.to_vec();
+        block.resize(BLOCK_SIZE, 0xFF);
+        let checksum = block.iter().fold(CHECKSUM_SEED, |acc, x| acc ^ x) as u32;
+        let mut data = words(&[BLOCK_SIZE as u32, seq as u32, 0, 0]);
+        data.extend(&block);
+        loader.command(FLASH_DATA, &data, checksum, COMMAND_TIMEOUT)?;
+        progress(
+            (seq * BLOCK_SIZE + chunk.len()).min(image.len()),
+            image.len(),
+        );
+    }
+
+    verify(loader, offset, image)?;
+
+    // 1 keeps the loader running, the hard reset below starts the firmware
+    loader.command(FLASH_END, &words(&[1]), 0, COMMAND_TIMEOUT)?;
+    Ok(())
+}
+
+// The ROM answers with the digest as 32 hex characters. It reads whole words,
+// the last block was padded with 0xFF when written
+fn verify(loader: &mut Loader, offset: u32, image: &[u8]) -> Result<(), String> {
+    let mut image = image.to_vec();
+    image.resize(image.len().next_multiple_of(4), 0xFF);
+    let timeout = COMMAND_TIMEOUT.max(MD5_TIMEOUT_PER_MB.mul_f32(image.len() as f32 / 1_000_000.0));
+    let (_, digest) = loader.command_data(
+        SPI_FLASH_MD5,
+        &words(&[offset, image.len() as u32, 0, 0]),
+        0,
+        timeout,
+    )?;
+    let expected: String = md5(&image).iter().map(|x| format!("{:02x}", x)).collect();
+    if !String::from_utf8_lossy(&digest).eq_ignore_ascii_case(&expected) {
+        return Err(format!(
+            "Flash verification failed, MD5 is {} instead of {}",
+            String::from_utf8_lossy(&digest),
+            expected
+        ));
+    }
+    debug!("Flash MD5 {} matches", expected);
+    Ok(())
+}
+
+// Resets into the bootloader, writes the image and resets back into the firmware.
+// The port ends at its original baud rate.
+pub fn flash(
+    port: &mut dyn SerialPort,
+    read: &mut dyn FnMut(Duration) -> Option<Vec<u8>>,
+    offset: u32,
+    image: &[u8],
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), String> {
+    if image.is_empty() {
+        return Err("The firmware image is empty".to_string());
+    }
+    let baud_rate = port.baud_rate().unwrap_or(ROM_BAUD_RATE);
+    if port.set_baud_rate(ROM_BAUD_RATE).is_err() {
+        return Err("Failed to set the bootloader baud rate".to_string());
+    }
+    enter_bootloader(port);
+    let _ = port.clear(ClearBuffer::Input);
+
+    let mut loader = Loader {
+        port,
+        read,
+        buf: Vec::new(),
+    };
+    let res = write_image(&mut loader, baud_rate, offset, image, progress);
+
+    if loader.port.set_baud_rate(baud_rate).is_err() {
+        error!("Failed to restore baud rate {}", baud_rate);
+    }
+    hard_reset(loader.port);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::{DataBits, FlowControl, Parity, StopBits};
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Chip {
+        // What the port has sent back, read through the read callback
+        replies: VecDeque<u8>,
+        written: Vec<u8>,
+        begin: Option<(u32, usize)>,
+        flash: Vec<u8>,
+        ended: bool,
+        // Flips a flashed byte, like a bad write
+        corrupt: bool,
+    }
+
+    impl Chip {
+        fn reply(&mut self, opcode: u8, value: u32, data: &[u8], status: u8) {
+            let mut packet = vec![0x01, opcode];
+            packet.extend(((data.len() + STATUS_LEN) as u16).to_le_bytes());
+            packet.extend(value.to_le_bytes());
+            packet.extend(data);
+            packet.extend([status, if status == 0 { 0 } else { 0x05 }, 0, 0]);
+            self.replies.extend(slip_encode(&packet));
+        }
+
+        fn word(data: &[u8], index: usize) -> u32 {
+            u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+        }
+
+        fn handle(&mut self, packet: &[u8]) {
+            let opcode = packet[1];
+            let checksum = Self::word(&packet[4..8], 0);
+            let data = &packet[8..];
+            match opcode {
+                SYNC => {
+                    // The ROM answers a sync several times
+                    for _ in 0..3 {
+                        self.reply(SYNC, 0, &[], 0);
+                    }
+                }
+                READ_REG => self.reply(READ_REG, ESP32_MAGIC, &[], 0),
+                SPI_ATTACH | SPI_SET_PARAMS => self.reply(opcode, 0, &[], 0),
+                FLASH_BEGIN => {
+                    let size = Self::word(data, 0) as usize;
+                    self.begin = Some((Self::word(data, 3), size));
+                    self.flash.clear();
+                    self.reply(FLASH_BEGIN, 0, &[], 0);
+                }
+                FLASH_DATA => {
+                    let block = &data[16..];
+                    let expected = block.iter().fold(CHECKSUM_SEED, |acc, x| acc ^ x) as u32;
+                    let seq = Self::word(data, 1) as usize;
+                    if checksum != expected || seq * BLOCK_SIZE != self.flash.len() {
+                        self.reply(FLASH_DATA, 0, &[], 1);
+                        return;
+                    }
+                    self.flash.extend(block);
+                    if self.corrupt {
+                        self.flash[0] ^= 0x01;
+                    }
+                    self.reply(FLASH_DATA, 0, &[], 0);
+                }
+                SPI_FLASH_MD5 => {
+                    let offset = Self::word(data, 0);
+                    let size = Self::word(data, 1) as usize;
+                    let start = (offset - self.begin.unwrap().0) as usize;
+                    let digest: String = md5(&self.flash[start..start + size])
+                        .iter()
+                        .map(|x| format!("{:02x}", x))
+                        .collect();
+                    self.reply(SPI_FLASH_MD5, 0, digest.as_bytes(), 0);
+                }
+                FLASH_END => {
+                    self.ended = true;
+                    self.reply(FLASH_END, 0, &[], 0);
+                }
+                _ => self.reply(opcode, 0, &[], 1),
+            }
+        }
+    }
+
+    struct MockPort {
+        chip: Arc<Mutex<Chip>>,
+        baud_rate: u32,
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut chip = self.chip.lock().unwrap();
+            chip.written.extend(buf);
+            // Every command is one SLIP frame
+            while let Some(end) = chip.written.iter().skip(1).position(|x| *x == 0xC0) {
+                let frame: Vec<u8> = chip.written.drain(..end + 2).collect();
+                chip.handle(&slip_decode(&frame[1..frame.len() - 1]));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl SerialPort for MockPort {
+        fn name(&self) -> Option<String> {
+            Some("mock".to_string())
+        }
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(self.baud_rate)
+        }
+        fn data_bits(&self) -> serialport::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+        fn flow_control(&self) -> serialport::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+        fn parity(&self) -> serialport::Result<Parity> {
+            Ok(Parity::None)
+        }
+        fn stop_bits(&self) -> serialport::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            Duration::ZERO
+        }
+        fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+            self.baud_rate = baud_rate;
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "Not supported",
+            ))
+        }
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flash_mock(image: &[u8], corrupt: bool) -> (Result<(), String>, Chip, usize) {
+        let chip = Arc::new(Mutex::new(Chip {
+            corrupt,
+            ..Default::default()
+        }));
+        let mut port = MockPort {
+            chip: chip.clone(),
+            baud_rate: ROM_BAUD_RATE,
+        };
+        let chip_read = chip.clone();
+        let mut read = move |_timeout: Duration| {
+            let mut chip = chip_read.lock().unwrap();
+            if chip.replies.is_empty() {
+                return None;
+            }
+            Some(chip.replies.drain(..).collect())
+        };
+        let mut written = 0;
+        let res = flash(&mut port, &mut read, 0x10000, image, &mut |x, _| {
+            written = x
+        });
+        let chip = std::mem::take(&mut *chip.lock().unwrap());
+        (res, chip, written)
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        let packet = [0x01, 0xC0, 0x02, 0xDB, 0xDC, 0xDD, 0xC0, 0xDB];
+        let frame = slip_encode(&packet);
+        assert_eq!(
+            frame,
+            [0xC0, 0x01, 0xDB, 0xDC, 0x02, 0xDB, 0xDD, 0xDC, 0xDD, 0xDB, 0xDC, 0xDB, 0xDD, 0xC0]
+        );
+        assert_eq!(slip_decode(&frame[1..frame.len() - 1]), packet);
+        assert!(slip_decode(&[]).is_empty());
+    }
+
+    #[test]
+    fn md5_matches_known_digests() {
+        let hex = |x: [u8; 16]| -> String { x.iter().map(|x| format!("{:02x}", x)).collect() };
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(hex(md5(&[b'a'; 1000])), "cabe45dcc9ae5b66ba86600cca6b8ba8");
+    }
+
+    #[test]
+    fn flashes_and_verifies_image() {
+        // Not a whole number of blocks and full of SLIP bytes
+        let image: Vec<u8> = (0..2 * BLOCK_SIZE + 123)
+            .map(|x| [0xC0, 0xDB, x as u8][x % 3])
+            .collect();
+        let (res, chip, written) = flash_mock(&image, false);
+        assert_eq!(res, Ok(()));
+        assert_eq!(chip.begin, Some((0x10000, image.len())));
+        assert_eq!(&chip.flash[..image.len()], image);
+        assert!(chip.flash[image.len()..].iter().all(|x| *x == 0xFF));
+        assert!(chip.ended);
+        assert_eq!(written, image.len());
+    }
+
+    #[test]
+    fn reports_a_bad_write() {
+        let image = vec![0x42; 100];
+        let (res, chip, _) = flash_mock(&image, true);
+        assert!(res.unwrap_err().contains("MD5"));
+        assert!(!chip.ended);
+    }
+}
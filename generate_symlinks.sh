@@ -2,8 +2,20 @@
 cd ws-serial-tcp/src
 ln -P ../../serial.rs serial.rs
 ln -P ../../api.rs api.rs
+ln -P ../../bitmap.rs bitmap.rs
+ln -P ../../codec.rs codec.rs
+ln -P ../../parser.rs parser.rs
+ln -P ../../flash.rs flash.rs
+ln -P ../../profile.rs profile.rs
+ln -P ../../discovery.rs discovery.rs
 cd ../../
 cd watchy-scom/src
 ln -P ../../serial.rs serial.rs
 ln -P ../../api.rs api.rs
+ln -P ../../bitmap.rs bitmap.rs
+ln -P ../../codec.rs codec.rs
+ln -P ../../parser.rs parser.rs
+ln -P ../../flash.rs flash.rs
+ln -P ../../profile.rs profile.rs
+ln -P ../../discovery.rs discovery.rs
 cd ../../
\ No newline at end of file
@@ -0,0 +1,203 @@
+use memchr::memmem::Finder;
+
+// Bigger than the screen of any profile, past it the end packet isn't coming
+const MAX_BUFFER: usize = 1024 * 1024;
+
+pub enum ParserEvent<'a> {
+    // First end packet after connecting, everything before it is garbage
+    Synced,
+    Logs(&'a [u8]),
+    Screen(&'a [u8]),
+    // A frame that lost its end packet, thrown away when the next one starts
+    Resynced(&'a [u8]),
+}
+
+// Splits the serial stream into logs and screens between the start and end packets.
+// Bytes are searched only once, the buffer keeps its allocation between frames.
+pub struct FrameParser {
+    buf: Vec<u8>,
+    scanned: usize,
+    synced: bool,
+    start_packet: Finder<'static>,
+    end_packet: Finder<'static>,
+}
+
+impl FrameParser {
+    // An empty marker is found everywhere, nothing would ever be read
+    pub fn new(start_packet: &[u8], end_packet: &[u8]) -> Result<Self, String> {
+        if start_packet.is_empty() || end_packet.is_empty() {
+            return Err("Frame markers can't be empty".to_string());
+        }
+        Ok(Self {
+            buf: Vec::with_capacity(16000), // 15000 is screen size
+            scanned: 0,
+            synced: false,
+            start_packet: Finder::new(start_packet).into_owned(),
+            end_packet: Finder::new(end_packet).into_owned(),
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.scanned = 0;
+        self.synced = false;
+    }
+
+    pub fn push(&mut self, data: &[u8], mut on_event: impl FnMut(ParserEvent)) {
+        self.buf.extend_from_slice(data);
+        let end_len = self.end_packet.needle().len();
+        let start_len = self.start_packet.needle().len();
+
+        loop {
+            let Some(found) = self.end_packet.find(&self.buf[self.scanned..]) else {
+                if self.buf.len() > MAX_BUFFER {
+                    // Keeps a tail in case a marker is split there
+                    let flushed = self.buf.len() - (start_len.max(end_len) - 1);
+                    on_event(ParserEvent::Logs(&self.buf[..flushed]));
+                    self.buf.drain(..flushed);
+                }
+                // The marker could be split between reads
+                self.scanned = self.buf.len().saturating_sub(end_len - 1);
+                return;
+            };
+            let end_pos = self.scanned + found;
+
+            if !self.synced {
+                self.synced = true;
+                on_event(ParserEvent::Synced);
+            } else if let Some(start_pos) = self.start_packet.find(&self.buf[..end_pos]) {
+                if start_pos > 0 {
+                    on_event(ParserEvent::Logs(&self.buf[..start_pos]));
+                }
+                // The screen belongs to the last start packet before the end
+                let mut frame_pos = start_pos;
+                while let Some(found) = self
+                    .start_packet
+                    .find(&self.buf[frame_pos + start_len..end_pos])
+                {
+                    frame_pos += start_len + found;
+                }
+                if frame_pos > start_pos {
+                    on_event(ParserEvent::Resynced(&self.buf[start_pos..frame_pos]));
+                }
+                on_event(ParserEvent::Screen(
+                    &self.buf[frame_pos + start_len..end_pos],
+                ));
+            } else if end_pos > 0 {
+                // The start packet was missed or the firmware logged the marker
+                on_event(ParserEvent::Logs(&self.buf[..end_pos]));
+            }
+
+            self.buf.drain(..end_pos + end_len);
+            self.scanned = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &[u8] = b"thisisastartpack";
+    const END: &[u8] = b"thisisaendddpack";
+
+    fn events(parser: &mut FrameParser, chunks: &[&[u8]]) -> Vec<String> {
+        let mut events = Vec::new();
+        for chunk in chunks {
+            parser.push(chunk, |event| {
+                events.push(match event {
+                    ParserEvent::Synced => "synced".to_string(),
+                    ParserEvent::Logs(x) => format!("logs {}", String::from_utf8_lossy(x)),
+                    ParserEvent::Screen(x) => format!("screen {}", String::from_utf8_lossy(x)),
+                    ParserEvent::Resynced(x) => {
+                        format!("resynced {}", String::from_utf8_lossy(x))
+                    }
+                })
+            });
+        }
+        events
+    }
+
+    fn synced_parser() -> FrameParser {
+        let mut parser = FrameParser::new(START, END).unwrap();
+        assert_eq!(
+            events(&mut parser, &[b"garbagethisisaendddpack"]),
+            ["synced"]
+        );
+        parser
+    }
+
+    #[test]
+    fn refuses_empty_markers() {
+        assert!(FrameParser::new(b"", END).is_err());
+        assert!(FrameParser::new(START, b"").is_err());
+    }
+
+    #[test]
+    fn finds_markers_split_between_chunks() {
+        let mut parser = synced_parser();
+        let chunks: [&[u8]; 4] = [b"log\nthisisast", b"artpackab", b"cdthisisaen", b"dddpack"];
+        assert_eq!(events(&mut parser, &chunks), ["logs log\n", "screen abcd"]);
+    }
+
+    #[test]
+    fn splits_back_to_back_frames() {
+        let mut parser = synced_parser();
+        let stream = [START, b"one", END, START, b"two", END, b"rest"].concat();
+        assert_eq!(
+            events(&mut parser, &[&stream]),
+            ["screen one", "screen two"]
+        );
+        // The logs after the last frame wait for the next end marker
+        let stream = [START, b"three", END].concat();
+        assert_eq!(
+            events(&mut parser, &[&stream]),
+            ["logs rest", "screen three"]
+        );
+    }
+
+    #[test]
+    fn treats_end_without_start_as_logs() {
+        let mut parser = synced_parser();
+        let stream = [b"lost start".as_slice(), END, START, b"ok", END].concat();
+        assert_eq!(
+            events(&mut parser, &[&stream]),
+            ["logs lost start", "screen ok"]
+        );
+    }
+
+    #[test]
+    fn drops_frames_without_end() {
+        let mut parser = synced_parser();
+        let stream = [b"log".as_slice(), START, b"lost end", START, b"ok", END].concat();
+        assert_eq!(
+            events(&mut parser, &[&stream]),
+            ["logs log", "resynced thisisastartpacklost end", "screen ok"]
+        );
+    }
+
+    #[test]
+    fn flushes_logs_when_no_end_comes() {
+        let mut parser = synced_parser();
+        let chunk = vec![b'a'; 64 * 1024];
+        let mut logs = 0;
+        for _ in 0..MAX_BUFFER / chunk.len() + 1 {
+            parser.push(&chunk, |event| {
+                if let ParserEvent::Logs(x) = event {
+                    logs += x.len();
+                }
+            });
+        }
+        assert_eq!(logs, MAX_BUFFER + chunk.len() - (START.len() - 1));
+        assert!(parser.buf.len() < START.len());
+        // Frames still come through afterwards
+        let stream = [START, b"ok", END].concat();
+        assert_eq!(
+            events(&mut parser, &[&stream]),
+            [
+                format!("logs {}", "a".repeat(START.len() - 1)),
+                "screen ok".to_string()
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// Everything that differs between firmwares, loaded from a TOML file:
+//
+// name = "inkwatchy"
+// width = 200
+// height = 200
+// bit_order = "msb" # of the pixels in each byte, "msb" or "lsb"
+// screen_command = "screen:"
+// reset_command = "reset:"
+// start_marker = "thisisastartpack"
+// end_marker = "thisisaendddpack"
+// log_regex = '^(?P<file>src/[^:\s]+):(?P<line>\d+):?\s*(?P<message>.*)$'
+//
+// [[buttons]]
+// label = "Menu"
+// command = "menu-button:"
+// key = "Enter" # optional, egui key name
+//
+// Missing fields keep the InkWatchy values. Profiles are picked by name, built in
+// ones first, then PROFILE_DIR/<name>.toml, or by path.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::bitmap;
+
+pub const DEFAULT_PROFILE: &str = "inkwatchy";
+pub const PROFILE_DIR: &str = "profiles";
+// Bigger than any e-paper, keeps a screen buffer under a megabyte
+pub const MAX_SIDE: u16 = 2048;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitOrder {
+    Msb,
+    Lsb,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Button {
+    pub label: String,
+    pub command: String,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    pub width: u16,
+    pub height: u16,
+    pub bit_order: BitOrder,
+    pub buttons: Vec<Button>,
+    pub screen_command: String,
+    pub reset_command: String,
+    // Around every screen the watch sends
+    pub start_marker: String,
+    pub end_marker: String,
+    // Named groups file, line and message, other lines only show as raw logs
+    pub log_regex: String,
+}
+
+fn button(label: &str, command: &str, key: Option<&str>) -> Button {
+    Button {
+        label: label.to_string(),
+        command: command.to_string(),
+        key: key.map(|x| x.to_string()),
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+            width: bitmap::WIDTH as u16,
+            height: bitmap::HEIGHT as u16,
+            bit_order: BitOrder::Msb,
+            buttons: vec![
+                button("Back", "back-button:", Some("Backspace")),
+                button("Menu", "menu-button:", Some("Enter")),
+                button("Up", "up-button:", Some("ArrowUp")),
+                button("Down", "down-button:", Some("ArrowDown")),
+                button("Long back", "long-back-button:", None),
+                button("Long menu", "long-menu-button:", None),
+                button("Long up", "long-up-button:", None),
+                button("Long down", "long-down-button:", None),
+            ],
+            screen_command: "screen:".to_string(),
+            reset_command: "reset:".to_string(),
+            start_marker: "thisisastartpack".to_string(),
+            end_marker: "thisisaendddpack".to_string(),
+            // src/hardware/rtc.cpp:45: message, the function name is optional
+            log_regex: r"^(?P<file>src/[^:\s]+):(?P<line>\d+):?\s*(?P<message>.*)$".to_string(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn packed_len(&self) -> usize {
+        bitmap::packed_len(self.width as usize, self.height as usize)
+    }
+
+    // Profiles also arrive over the network, check them before using them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_marker.is_empty() || self.end_marker.is_empty() {
+            return Err(format!("Profile {} has an empty marker", self.name));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("Profile {} has an empty screen", self.name));
+        }
+        if self.width > MAX_SIDE || self.height > MAX_SIDE {
+            return Err(format!(
+                "Profile {} has a {}x{} screen, at most {}x{} is supported",
+                self.name, self.width, self.height, MAX_SIDE, MAX_SIDE
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            DEFAULT_PROFILE => Some(Self::default()),
+            _ => None,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|x| format!("Failed to read {}: {}", path.display(), x))?;
+        let table: toml::Table = toml::from_str(&text)
+            .map_err(|x| format!("Failed to parse {}: {}", path.display(), x))?;
+        let mut profile: Profile = table
+            .clone()
+            .try_into()
+            .map_err(|x| format!("Failed to parse {}: {}", path.display(), x))?;
+        // Unnamed profiles are called after their file
+        if !table.contains_key("name") {
+            if let Some(stem) = path.file_stem() {
+                profile.name = stem.to_string_lossy().to_string();
+            }
+        }
+        profile
+            .validate()
+            .map_err(|x| format!("{}: {}", path.display(), x))?;
+        Ok(profile)
+    }
+
+    // A built in name, a name from PROFILE_DIR or a path
+    pub fn find(name: &str) -> Result<Self, String> {
+        if let Some(profile) = Self::builtin(name) {
+            return Ok(profile);
+        }
+        let in_dir = Path::new(PROFILE_DIR).join(format!("{}.toml", name));
+        if in_dir.exists() {
+            return Self::load(&in_dir);
+        }
+        Self::load(Path::new(name))
+    }
+
+    // Names for find(), built in ones first
+    pub fn available() -> Vec<String> {
+        let mut names = vec![DEFAULT_PROFILE.to_string()];
+        if let Ok(entries) = std::fs::read_dir(PROFILE_DIR) {
+            let mut files: Vec<String> = entries
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| x.extension().is_some_and(|x| x == "toml"))
+                .filter_map(|x| Some(x.file_stem()?.to_string_lossy().to_string()))
+                .filter(|x| !names.contains(x))
+                .collect();
+            files.sort();
+            names.extend(files);
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_screen_and_markers() {
+        assert!(Profile::default().validate().is_ok());
+        let with = |f: fn(&mut Profile)| {
+            let mut profile = Profile::default();
+            f(&mut profile);
+            profile.validate()
+        };
+        assert!(with(|x| x.start_marker.clear()).is_err());
+        assert!(with(|x| x.end_marker.clear()).is_err());
+        assert!(with(|x| x.width = 0).is_err());
+        assert!(with(|x| x.height = 0).is_err());
+        assert!(with(|x| x.width = u16::MAX).is_err());
+        assert!(with(|x| x.height = MAX_SIDE + 1).is_err());
+        assert!(with(|x| (x.width, x.height) = (MAX_SIDE, MAX_SIDE)).is_ok());
+    }
+}
@@ -1,52 +1,356 @@
-use image::{ImageBuffer, ImageFormat, Rgb};
 use log::{debug, error, info};
-use serialport::{self, SerialPort};
+use serialport::{self, ClearBuffer, DataBits, Parity, SerialPort, StopBits};
 use std::{
-    io::Read,
-    sync::mpsc::{Receiver, Sender},
+    collections::VecDeque,
+    io::{ErrorKind, Read},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    sync::Arc,
     thread,
-    time::{self, Duration},
+    time::{self, Duration, Instant},
 };
 
 use crate::serial::SendToGui::*;
 use crate::serial::SendToSerial::*;
 
-use crate::api::{SendToGui, SendToSerial};
+use crate::api::{LinkStats, SendToGui, SendToSerial};
+use crate::bitmap;
+use crate::codec;
+use crate::flash;
+use crate::parser::{FrameParser, ParserEvent};
+use crate::profile::{BitOrder, Profile};
 
-fn find_subsequence(vector: &[u8], subsequence: &[u8]) -> Option<usize> {
-    if subsequence.len() as isize > vector.len() as isize - subsequence.len() as isize {
+// The watch sends the screen between the start and end packets as one of:
+// - exactly the packed length of the profile screen, the raw frame
+// - b'R' + RLE (see codec.rs) of the whole frame
+// - b'D' + x, y, width, height (u16 little endian, x and width multiples of 8) + the packed rows of that window
+// Compressed payloads are only sent when they are shorter than a raw frame, so the length decides
+pub fn decode_screen(
+    previous: &[u8],
+    payload: &[u8],
+    width: usize,
+    height: usize,
+) -> Option<Vec<u8>> {
+    let packed_len = bitmap::packed_len(width, height);
+    if payload.len() == packed_len {
+        return Some(payload.to_vec());
+    }
+    match payload.first() {
+        Some(b'R') => match codec::rle_decode(&payload[1..]) {
+            Some(x) if x.len() == packed_len => Some(x),
+            _ => {
+                error!("Failed to decode RLE screen");
+                None
+            }
+        },
+        Some(b'D') => {
+            let res = apply_window(previous, &payload[1..], width, height);
+            if res.is_none() {
+                error!("Failed to decode screen window");
+            }
+            res
+        }
+        _ => None,
+    }
+}
+
+// Rows only start on a byte when the screen width is a multiple of 8
+fn apply_window(
+    previous: &[u8],
+    data: &[u8],
+    screen_width: usize,
+    screen_height: usize,
+) -> Option<Vec<u8>> {
+    if data.len() < 8
+        || !screen_width.is_multiple_of(8)
+        || previous.len() != bitmap::packed_len(screen_width, screen_height)
+    {
+        return None;
+    }
+    let field = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as usize;
+    let (x, y, width, height) = (field(0), field(1), field(2), field(3));
+    if width == 0
+        || x % 8 != 0
+        || width % 8 != 0
+        || x + width > screen_width
+        || y + height > screen_height
+    {
+        return None;
+    }
+    let row_bytes = width / 8;
+    let rows = &data[8..];
+    if rows.len() != row_bytes * height {
+        return None;
+    }
+    let mut screen = previous.to_vec();
+    for (row, bytes) in rows.chunks_exact(row_bytes).enumerate() {
+        let start = ((y + row) * screen_width + x) / 8;
+        screen[start..start + row_bytes].copy_from_slice(bytes);
+    }
+    Some(screen)
+}
+
+pub fn parse_config(config: &str) -> Option<(DataBits, Parity, StopBits)> {
+    let config = config.as_bytes();
+    if config.len() != 3 {
         return None;
     }
+    let data_bits = match config[0] {
+        b'5' => DataBits::Five,
+        b'6' => DataBits::Six,
+        b'7' => DataBits::Seven,
+        b'8' => DataBits::Eight,
+        _ => return None,
+    };
+    let parity = match config[1].to_ascii_uppercase() {
+        b'N' => Parity::None,
+        b'E' => Parity::Even,
+        b'O' => Parity::Odd,
+        _ => return None,
+    };
+    let stop_bits = match config[2] {
+        b'1' => StopBits::One,
+        b'2' => StopBits::Two,
+        _ => return None,
+    };
+    Some((data_bits, parity, stop_bits))
+}
 
-    for i in 0..=(vector.len() - subsequence.len()) {
-        if vector[i..].starts_with(subsequence) {
-            //debug!("Found subsequence of: {} in vec, it looks like: {}", String::from_utf8_lossy(subsequence), String::from_utf8_lossy(&vector[i..i + subsequence.len()]));
-            //debug!("It's at position: {}", i);
-            //debug!("Pure dump of this vector: {:?}", String::from_utf8_lossy(&vector));
-            return Some(i);
+// Opens the port unless it is open already and starts reading it, true when the
+// parsing state should start over
+fn open_port(
+    port: &mut Option<Box<dyn SerialPort>>,
+    reader_stop: &mut Option<Arc<AtomicBool>>,
+    tx_event: &Sender<SerialEvent>,
+    profile: &Profile,
+    port_name: String,
+    baud_rate: usize,
+    config: &str,
+) -> bool {
+    debug!("Received open port: {} {} {}", port_name, baud_rate, config);
+    if let Some(ref mut rport) = port {
+        debug!("Currently used port name: {:?}", rport.name());
+        if port_name == rport.name().unwrap() {
+            debug!("The same port is already selected, skipping");
+            return false;
         }
     }
-    None
+    let Some((data_bits, parity, stop_bits)) = parse_config(config) else {
+        error!("Invalid serial config: {}", config);
+        return false;
+    };
+    let res = serialport::new(port_name, baud_rate as u32)
+        .data_bits(data_bits)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .timeout(Duration::from_millis(9999999))
+        .open();
+    if res.is_err() {
+        error!("Failed to open port, reason: {:?}", res);
+        return false;
+    }
+    *port = Some(res.unwrap());
+    if let Some(stop) = reader_stop.take() {
+        stop.store(true, Ordering::Relaxed);
+    }
+    match port.as_ref().unwrap().try_clone() {
+        Ok(reader) => *reader_stop = Some(spawn_reader(reader, tx_event.clone())),
+        Err(x) => error!("Failed to clone port for reading: {}", x),
+    }
+    thread::sleep(time::Duration::from_millis(100));
+    if let Some(ref mut rport) = port {
+        if rport.write_all(profile.screen_command.as_bytes()).is_err() {
+            error!("Failed to write screen message");
+        }
+        if rport.flush().is_err() {
+            error!("Failed to flush");
+        };
+    }
+    true
+}
+
+// Raw access to the port for one client, frame parsing pauses until Stop
+pub enum PassThrough {
+    // Port data goes to this sender instead of the parser
+    Start(Sender<Vec<u8>>),
+    Write(Vec<u8>),
+    SetBaudRate(u32),
+    SetDataBits(DataBits),
+    SetParity(Parity),
+    SetStopBits(StopBits),
+    SetDtr(bool),
+    SetRts(bool),
+    Purge,
+    // Restores the port settings from Start
+    Stop,
+}
+
+enum SerialEvent {
+    Command(SendToSerial),
+    // With the time it was read
+    Data(Vec<u8>, Instant),
+    PassThrough(PassThrough),
+    // Nobody sends commands anymore, the port is closed
+    Closed,
+}
+
+#[derive(Default)]
+struct LinkCounter {
+    bytes: usize,
+    frames: usize,
+    latency: Duration,
+    wrong_length_frames: u64,
+    resyncs: u64,
+}
+
+impl LinkCounter {
+    fn report(&mut self, elapsed: Duration) -> LinkStats {
+        let seconds = elapsed.as_secs_f32().max(0.001);
+        let stats = LinkStats {
+            bytes_per_sec: self.bytes as f32 / seconds,
+            frames_per_sec: self.frames as f32 / seconds,
+            decode_latency_ms: if self.frames > 0 {
+                self.latency.as_secs_f32() * 1000.0 / self.frames as f32
+            } else {
+                0.0
+            },
+            wrong_length_frames: self.wrong_length_frames,
+            resyncs: self.resyncs,
+        };
+        self.bytes = 0;
+        self.frames = 0;
+        self.latency = Duration::ZERO;
+        stats
+    }
+}
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Blocks on the port and forwards whatever arrives, until stop is set
+fn spawn_reader(mut reader: Box<dyn SerialPort>, tx_event: Sender<SerialEvent>) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_reader = stop.clone();
+    // Only bounds how long a replaced reader lingers, data returns immediately
+    if reader.set_timeout(Duration::from_millis(200)).is_err() {
+        error!("Failed to set reader timeout");
+    }
+    thread::spawn(move || {
+        let mut serial_buf_tmp: Vec<u8> = vec![0; 7000];
+        while !stop_reader.load(Ordering::Relaxed) {
+            match reader.read(serial_buf_tmp.as_mut_slice()) {
+                Ok(0) => {}
+                Ok(readed) => {
+                    if tx_event
+                        .send(SerialEvent::Data(
+                            serial_buf_tmp[0..readed].to_vec(),
+                            Instant::now(),
+                        ))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(x) if x.kind() == ErrorKind::TimedOut => {}
+                Err(x) => {
+                    error!("Failed to read from port: {}", x);
+                    break;
+                }
+            }
+        }
+        debug!("Reader thread stopped");
+    });
+    stop
 }
 
 pub fn main(tx_gui: Sender<SendToGui>, rx_serial: Receiver<SendToSerial>) {
+    run(tx_gui, rx_serial, None);
+}
+
+pub fn main_with_passthrough(
+    tx_gui: Sender<SendToGui>,
+    rx_serial: Receiver<SendToSerial>,
+    rx_passthrough: Receiver<PassThrough>,
+) {
+    run(tx_gui, rx_serial, Some(rx_passthrough));
+}
+
+fn run(
+    tx_gui: Sender<SendToGui>,
+    rx_serial: Receiver<SendToSerial>,
+    rx_passthrough: Option<Receiver<PassThrough>>,
+) {
     let mut port: Option<Box<dyn SerialPort>> = None;
-    let mut serial_buf: Vec<u8> = Vec::with_capacity(16000); // 15000 is screen size
-    let mut synced = false;
-
-    let packets_length = 16;
-    // thisisastartpacket
-    let start_packet: Vec<u8> = vec![
-        116, 104, 105, 115, 105, 115, 97, 115, 116, 97, 114, 116, 112, 97, 99, 107,
-    ];
-    // thisisaendddpacket
-    let end_packet: Vec<u8> = vec![
-        116, 104, 105, 115, 105, 115, 97, 101, 110, 100, 100, 100, 112, 97, 99, 107,
-    ];
+    // Markers, commands and screen of the firmware, replaced with SetProfile
+    let mut profile = Profile::default();
+    let mut last_screen: Vec<u8> = vec![0xFF; profile.packed_len()]; // White
+    let mut parser = FrameParser::new(
+        profile.start_marker.as_bytes(),
+        profile.end_marker.as_bytes(),
+    )
+    .expect("Default profile has markers");
+
+    // Commands and port data wake this thread up through one channel
+    let (tx_event, rx_event) = mpsc::channel();
+    let tx_event_commands = tx_event.clone();
+    thread::spawn(move || {
+        for command in rx_serial {
+            if tx_event_commands
+                .send(SerialEvent::Command(command))
+                .is_err()
+            {
+                break;
+            }
+        }
+        let _ = tx_event_commands.send(SerialEvent::Closed);
+    });
+    if let Some(rx_passthrough) = rx_passthrough {
+        let tx_event_passthrough = tx_event.clone();
+        thread::spawn(move || {
+            for x in rx_passthrough {
+                if tx_event_passthrough
+                    .send(SerialEvent::PassThrough(x))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+    let mut passthrough: Option<Sender<Vec<u8>>> = None;
+    let mut saved_settings: Option<(u32, DataBits, Parity, StopBits)> = None;
+    let mut reader_stop: Option<Arc<AtomicBool>> = None;
+
+    let mut counter = LinkCounter::default();
+    let mut last_report = Instant::now();
+    // Requests that came in while flashing, handled once it is done
+    let mut deferred: VecDeque<SerialEvent> = VecDeque::new();
 
     loop {
-        match rx_serial.recv_timeout(Duration::from_millis(40)) {
-            Ok(x) => match x {
+        // Reported even if the data never stops
+        if last_report.elapsed() >= STATS_INTERVAL {
+            let stats = counter.report(last_report.elapsed());
+            last_report = Instant::now();
+            if port.is_some() && tx_gui.send(Stats(stats)).is_err() {
+                error!("Failed to send stats to gui");
+            }
+        }
+        let event = match deferred.pop_front() {
+            Some(x) => x,
+            None => {
+                match rx_event.recv_timeout(STATS_INTERVAL.saturating_sub(last_report.elapsed())) {
+                    Ok(x) => x,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        };
+        match event {
+            SerialEvent::Command(x)
+                if passthrough.is_some() && !matches!(x, AskForPorts() | AskForDevices()) =>
+            {
+                debug!("Ignoring {:?} during pass-through", x);
+            }
+            SerialEvent::Command(x) => match x {
                 AskForPorts() => {
                     debug!("Received ask for ports");
                     match serialport::available_ports() {
@@ -61,47 +365,106 @@ pub fn main(tx_gui: Sender<SendToGui>, rx_serial: Receiver<SendToSerial>) {
                             }
                         }
                         Err(x) => {
-                            if tx_gui
-                                .send(LogToShow(format!("{}", x.to_string())))
-                                .is_err()
-                            {
+                            if tx_gui.send(LogToShow(x.to_string())).is_err() {
                                 error!("Failed to send LogToShow");
                             }
                         }
                     }
                 }
+                // Clients that don't know about data bits, parity and stop bits
                 SelectPort(port_name, baud_rate) => {
-                    debug!("Received select port: {}", port_name);
+                    if open_port(
+                        &mut port,
+                        &mut reader_stop,
+                        &tx_event,
+                        &profile,
+                        port_name,
+                        baud_rate,
+                        "8N1",
+                    ) {
+                        parser.reset();
+                        counter = LinkCounter::default();
+                    }
+                }
+                OpenPort(port_name, baud_rate, config) => {
+                    if open_port(
+                        &mut port,
+                        &mut reader_stop,
+                        &tx_event,
+                        &profile,
+                        port_name,
+                        baud_rate,
+                        &config,
+                    ) {
+                        parser.reset();
+                        counter = LinkCounter::default();
+                    }
+                }
+                SendMessage(x) => {
                     if let Some(ref mut rport) = port {
-                        debug!("Currently used port name: {:?}", rport.name());
-                        if port_name == rport.name().unwrap() {
-                            debug!("The same port is already selected, skipping");
-                            continue;
+                        debug!("Writing to serial port: {}", x);
+                        if rport.write_all(x.as_bytes()).is_err() {
+                            error!("Failed to write message: {}", x);
                         }
+                        if rport.flush().is_err() {
+                            error!("Failed to flush");
+                        };
+                    } else {
+                        error!("Failed to get rport");
+                    }
+                }
+                SendBytes(x) => {
+                    if let Some(ref mut rport) = port {
+                        debug!("Writing {} bytes to serial port: {:02x?}", x.len(), x);
+                        if rport.write_all(&x).is_err() {
+                            error!("Failed to write {} bytes", x.len());
+                        }
+                        if rport.flush().is_err() {
+                            error!("Failed to flush");
+                        };
+                    } else {
+                        error!("Failed to get rport");
                     }
-                    let res = serialport::new(port_name, baud_rate as u32)
-                        .timeout(Duration::from_millis(9999999))
-                        .open();
-                    if res.is_err() {
-                        error!("Failed to open port, reason: {:?}", res);
-                        std::process::exit(0);
+                }
+                SendImage(x) => {
+                    // The firmware reads exactly packed_len bytes after the start
+                    // marker, the image isn't escaped and may contain the end marker
+                    if x.len() != profile.packed_len() {
+                        error!(
+                            "Image has {} bytes, {} needs {}",
+                            x.len(),
+                            profile.name,
+                            profile.packed_len()
+                        );
+                        continue;
                     }
-                    port = Some(res.unwrap());
-                    thread::sleep(time::Duration::from_millis(100));
                     if let Some(ref mut rport) = port {
-                        if rport.write_all("screen:".as_bytes()).is_err() {
-                            error!("Failed to write screen message");
+                        debug!("Writing image to serial port, {} bytes", x.len());
+                        let mut message: Vec<u8> = "image:".as_bytes().to_vec();
+                        message.extend(profile.start_marker.as_bytes());
+                        // The gui always packs the most significant bit first
+                        let mut image = x;
+                        if profile.bit_order == BitOrder::Lsb {
+                            bitmap::reverse_bits(&mut image);
+                        }
+                        message.extend(&image);
+                        message.extend(profile.end_marker.as_bytes());
+                        if rport.write_all(&message).is_err() {
+                            error!("Failed to write image");
                         }
                         if rport.flush().is_err() {
                             error!("Failed to flush");
                         };
+                    } else {
+                        error!("Failed to get rport");
                     }
                 }
-                SendMessage(x) => {
+                Touch(x, y) => {
                     if let Some(ref mut rport) = port {
-                        debug!("Writing to serial port: {}", x);
-                        if rport.write_all(x.as_bytes()).is_err() {
-                            error!("Failed to write message: {}", x);
+                        let message = format!("touch-{}-{}:", x, y);
+                        debug!("Writing touch to serial port: {}", message);
+                        if rport.write_all(message.as_bytes()).is_err() {
+                            error!("Failed to write touch: {}", message);
                         }
                         if rport.flush().is_err() {
                             error!("Failed to flush");
@@ -110,119 +473,320 @@ pub fn main(tx_gui: Sender<SendToGui>, rx_serial: Receiver<SendToSerial>) {
                         error!("Failed to get rport");
                     }
                 }
+                Flash(offset, image) => {
+                    let Some(ref mut rport) = port else {
+                        error!("Failed to get rport");
+                        continue;
+                    };
+                    info!("Flashing {} bytes at {:#x}", image.len(), offset);
+                    let mut closed = false;
+                    // The bootloader answers through the reader thread
+                    let mut read = |timeout: Duration| {
+                        let deadline = Instant::now() + timeout;
+                        loop {
+                            let left = deadline.checked_duration_since(Instant::now())?;
+                            match rx_event.recv_timeout(left) {
+                                Ok(SerialEvent::Data(data, _)) => return Some(data),
+                                Ok(SerialEvent::Closed) => {
+                                    closed = true;
+                                    return None;
+                                }
+                                Ok(x) => deferred.push_back(x),
+                                Err(_) => return None,
+                            }
+                        }
+                    };
+                    let mut progress = |written, total| {
+                        let _ = tx_gui.send(FlashProgress(written, total));
+                    };
+                    let res =
+                        flash::flash(rport.as_mut(), &mut read, offset, &image, &mut progress);
+                    match &res {
+                        Ok(()) => info!("Flashing done"),
+                        Err(x) => error!("Flashing failed: {}", x),
+                    }
+                    if tx_gui.send(FlashDone(res)).is_err() {
+                        error!("Failed to send flash result to gui");
+                    }
+                    // The watch boots again, wait for the next end packet
+                    parser.reset();
+                    if closed {
+                        break;
+                    }
+                }
+                SetProfile(x) => {
+                    debug!(
+                        "Received profile {}, {}x{}, markers {} {}",
+                        x.name, x.width, x.height, x.start_marker, x.end_marker
+                    );
+                    if let Err(x) = x.validate() {
+                        error!("Refusing profile: {}", x);
+                        let _ = tx_gui.send(LogToShow(format!("Refusing profile: {}\n", x)));
+                        continue;
+                    }
+                    parser = FrameParser::new(x.start_marker.as_bytes(), x.end_marker.as_bytes())
+                        .expect("Validated profile has markers");
+                    if x.packed_len() != last_screen.len() {
+                        last_screen = vec![0xFF; x.packed_len()];
+                    }
+                    // Lets the gui follow profiles set by someone else, like ws-serial-tcp
+                    if tx_gui.send(SendToGui::Profile(x.clone())).is_err() {
+                        error!("Failed to send profile to gui");
+                    }
+                    profile = x;
+                }
+                AskForDevices() => {
+                    debug!("Devices are listed by ws-serial-tcp, ignoring");
+                }
             },
-            Err(_x) => {
-                /*
-                if _x.to_string() != "receiving on an empty channel" {
-                    error!("Failed to recv in serial: {}", _x);
+            SerialEvent::Closed => {
+                debug!("Command channel closed, stopping");
+                if let Some(stop) = reader_stop.take() {
+                    stop.store(true, Ordering::Relaxed);
                 }
-                */
+                break;
             }
-        }
-        if let Some(ref mut rport) = port {
-            //debug!("Reading from port...");
-            let mut serial_buf_tmp: Vec<u8> = vec![0; 7000];
-            if let Ok(btr) = rport.bytes_to_read() {
-                if btr == 0 {
+            SerialEvent::PassThrough(x) => {
+                let Some(ref mut rport) = port else {
+                    error!("Pass-through needs an open port");
                     continue;
+                };
+                let res = match x {
+                    PassThrough::Start(tx_data) => {
+                        info!("Pass-through started, frame parsing paused");
+                        saved_settings = Some((
+                            rport.baud_rate().unwrap_or(921600),
+                            rport.data_bits().unwrap_or(DataBits::Eight),
+                            rport.parity().unwrap_or(Parity::None),
+                            rport.stop_bits().unwrap_or(StopBits::One),
+                        ));
+                        passthrough = Some(tx_data);
+                        let _ = tx_gui.send(LogToShow(
+                            "Serial pass-through started, the screen is paused\n".to_string(),
+                        ));
+                        Ok(())
+                    }
+                    PassThrough::Write(data) => rport
+                        .write_all(&data)
+                        .and_then(|_| rport.flush())
+                        .map_err(serialport::Error::from),
+                    PassThrough::SetBaudRate(x) => rport.set_baud_rate(x),
+                    PassThrough::SetDataBits(x) => rport.set_data_bits(x),
+                    PassThrough::SetParity(x) => rport.set_parity(x),
+                    PassThrough::SetStopBits(x) => rport.set_stop_bits(x),
+                    PassThrough::SetDtr(x) => rport.write_data_terminal_ready(x),
+                    PassThrough::SetRts(x) => rport.write_request_to_send(x),
+                    PassThrough::Purge => rport.clear(ClearBuffer::All),
+                    PassThrough::Stop => {
+                        info!("Pass-through stopped, frame parsing resumed");
+                        passthrough = None;
+                        if let Some((baud_rate, data_bits, parity, stop_bits)) =
+                            saved_settings.take()
+                        {
+                            if rport.set_baud_rate(baud_rate).is_err()
+                                || rport.set_data_bits(data_bits).is_err()
+                                || rport.set_parity(parity).is_err()
+                                || rport.set_stop_bits(stop_bits).is_err()
+                            {
+                                error!("Failed to restore port settings");
+                            }
+                        }
+                        parser.reset();
+                        let _ = tx_gui.send(LogToShow("Serial pass-through stopped\n".to_string()));
+                        Ok(())
+                    }
+                };
+                if let Err(x) = res {
+                    error!("Failed pass-through request: {}", x);
                 }
             }
-            let _readed = rport.read(serial_buf_tmp.as_mut_slice()).unwrap();
-            //debug!("Readed bytes: {}", _readed);
-            //debug!("Pure dump: {}", String::from_utf8_lossy(&serial_buf_tmp));
-
-            let real_serial_buf_tmp = &serial_buf_tmp[0.._readed].to_owned();
-            serial_buf.extend(real_serial_buf_tmp);
-            if let Some(end_pos) = find_subsequence(&serial_buf, &end_packet) {
-                if !synced {
-                    synced = true;
-                    serial_buf.clear();
-                    debug!("SYNCED!");
-                    if rport.write_all("screen:".as_bytes()).is_err() {
-                        error!("Failed to write screen message");
-                    }
-                    if rport.flush().is_err() {
-                        error!("Failed to flush");
-                    };
+            SerialEvent::Data(data, readed_at) => {
+                let Some(ref mut rport) = port else {
                     continue;
-                }
-                if let Some(start_pos) = find_subsequence(&serial_buf, &start_packet) {
-                    debug!("it contains both packets!");
-                    debug!("start_pos :{}", start_pos);
-                    debug!("end_pos :{}", end_pos);
-                    debug!("serial_buf.len(): {}", serial_buf.len());
+                };
+                //debug!("Readed bytes: {}", data.len());
+                //debug!("Pure dump: {}", String::from_utf8_lossy(&data));
 
-                    if end_pos < start_pos {
-                        error!("End pos is above start pos, how? skipping...");
-                        serial_buf.clear();
-                        continue;
+                counter.bytes += data.len();
+                if let Some(tx_data) = &passthrough {
+                    // A gone client sends Stop itself
+                    let _ = tx_data.send(data);
+                    continue;
+                }
+                parser.push(&data, |event| match event {
+                    ParserEvent::Synced => {
+                        debug!("SYNCED!");
+                        if rport.write_all(profile.screen_command.as_bytes()).is_err() {
+                            error!("Failed to write screen message");
+                        }
+                        if rport.flush().is_err() {
+                            error!("Failed to flush");
+                        };
                     }
-                    //debug!("serial_buf len: {}", serial_buf.len());
-                    let logs = serial_buf[0..start_pos].to_owned();
-                    let screen = serial_buf[start_pos + packets_length..end_pos].to_owned();
-                    let rest = serial_buf[end_pos + packets_length..serial_buf.len()].to_owned();
-
-                    let real_logs = String::from_utf8_lossy(&logs);
-                    //debug!("Real logs: {}", real_logs);
-                    if !real_logs.is_empty() {
+                    ParserEvent::Resynced(dropped) => {
+                        debug!(
+                            "Dropped {} bytes of a frame without end packet",
+                            dropped.len()
+                        );
+                        counter.resyncs += 1;
+                    }
+                    ParserEvent::Logs(logs) => {
+                        let real_logs = String::from_utf8_lossy(logs);
+                        //debug!("Real logs: {}", real_logs);
                         if tx_gui.send(LogToShow(real_logs.to_string())).is_err() {
                             error!("Failed to send logs to gui");
                         }
                     }
+                    ParserEvent::Screen(screen) => {
+                        let screen = match decode_screen(
+                            &last_screen,
+                            screen,
+                            profile.width as usize,
+                            profile.height as usize,
+                        ) {
+                            Some(x) => x,
+                            None => {
+                                // Later deltas still apply to the last good screen
+                                error!("Screen len is: {}", screen.len());
+                                counter.wrong_length_frames += 1;
+                                return;
+                            }
+                        };
+                        last_screen = screen.clone();
+                        info!("Screen succesfully readed");
+
+                        //debug!("Real screen utf8: {}", String::from_utf8_lossy(&screen));
+                        //debug!("Real screen bytes: {:?}", screen);
 
-                    if screen.len() != 5000 {
-                        error!("Screen len is: {}", screen.len());
+                        // Rendering is left to the gui, which expects the most significant bit first
+                        let mut frame = screen;
+                        if profile.bit_order == BitOrder::Lsb {
+                            bitmap::reverse_bits(&mut frame);
+                        }
+                        // Parsing and decoding, up to the frame the gui can draw
+                        counter.frames += 1;
+                        counter.latency += readed_at.elapsed();
+                        if tx_gui
+                            .send(ShowFrame(profile.width, profile.height, frame))
+                            .is_err()
+                        {
+                            error!("Failed to send frame to gui");
+                        }
                     }
-                    info!("Screen succesfully readed");
+                });
+            }
+        }
+    }
+}
 
-                    //debug!("Real screen utf8: {}", String::from_utf8_lossy(&screen));
-                    //debug!("Real screen bytes: {:?}", screen);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    info!("Creating the image");
-                    let mut img = ImageBuffer::<Rgb<u8>, _>::new(200, 200);
-                    for y in 0..200 {
-                        for x in 0..200 {
-                            let index = (y * 200 + x) / 8;
-                            let bit_offset = 7 - ((y * 200 + x) % 8);
+    // 16x4, two bytes a row
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 4;
 
-                            let i_option = screen.get(index);
-                            if let Some(i) = i_option {
-                                let bit = (i >> bit_offset) & 1;
+    fn window(x: u16, y: u16, width: u16, height: u16, rows: &[u8]) -> Vec<u8> {
+        let mut payload = vec![b'D'];
+        for field in [x, y, width, height] {
+            payload.extend(field.to_le_bytes());
+        }
+        payload.extend(rows);
+        payload
+    }
 
-                                let color = if bit == 0 {
-                                    Rgb([0, 0, 0])
-                                } else {
-                                    Rgb([255, 255, 255])
-                                };
+    #[test]
+    fn decodes_raw_and_rle_screens() {
+        let previous = vec![0xFF; 8];
+        let raw: Vec<u8> = (0..8).collect();
+        assert_eq!(decode_screen(&previous, &raw, WIDTH, HEIGHT), Some(raw));
+        let mut rle = vec![b'R'];
+        rle.extend(codec::rle_encode(&[0xAA; 8]));
+        assert_eq!(
+            decode_screen(&previous, &rle, WIDTH, HEIGHT),
+            Some(vec![0xAA; 8])
+        );
+        // RLE of a screen of another size
+        let mut rle = vec![b'R'];
+        rle.extend(codec::rle_encode(&[0xAA; 6]));
+        assert_eq!(decode_screen(&previous, &rle, WIDTH, HEIGHT), None);
+    }
 
-                                img.put_pixel(x as u32, y as u32, color);
-                            } else {
-                                //error!("Error creating image, pixels missing");
-                                img.put_pixel(x as u32, y as u32, Rgb([255, 0, 0]));
-                            }
-                        }
-                    }
-                    let mut buffer = std::io::Cursor::new(Vec::new());
-                    img.write_to(&mut buffer, ImageFormat::Png).unwrap();
-                    if tx_gui.send(ShowPng(buffer.into_inner())).is_err() {
-                        error!("Failed to send png to gui");
-                    }
+    #[test]
+    fn applies_dirty_window() {
+        let previous = vec![0xFF; 8];
+        let payload = window(8, 1, 8, 2, &[0x01, 0x02]);
+        assert_eq!(
+            decode_screen(&previous, &payload, WIDTH, HEIGHT),
+            Some(vec![0xFF, 0xFF, 0xFF, 0x01, 0xFF, 0x02, 0xFF, 0xFF])
+        );
+    }
 
-                    serial_buf.clear();
-                    serial_buf.extend(rest);
-                } else {
-                    debug!("Found only end packet");
-                    let logs = &serial_buf[0..end_pos];
-                    let real_logs = String::from_utf8_lossy(&logs);
-                    if !real_logs.is_empty() {
-                        //debug!("Real logs: {}", real_logs);
-                        if tx_gui.send(LogToShow(real_logs.to_string())).is_err() {
-                            error!("Failed to send logs to gui");
-                        }
-                    }
-                    serial_buf.clear();
-                }
-            }
+    #[test]
+    fn refuses_bad_windows() {
+        let previous = vec![0xFF; 8];
+        // Runs past the right edge and past the bottom
+        let past_right = window(8, 0, 16, 1, &[0; 2]);
+        let past_bottom = window(0, 3, 8, 2, &[0; 2]);
+        // Doesn't start on a byte
+        let unaligned = window(4, 0, 8, 1, &[0]);
+        // Fewer rows than announced, or no header at all
+        let truncated = window(0, 0, 16, 2, &[0; 3]);
+        let no_header = vec![b'D', 0, 0];
+        let huge = window(u16::MAX, u16::MAX, u16::MAX, u16::MAX, &[]);
+        for payload in [
+            past_right,
+            past_bottom,
+            unaligned,
+            truncated,
+            no_header,
+            huge,
+        ] {
+            assert_eq!(decode_screen(&previous, &payload, WIDTH, HEIGHT), None);
         }
+        // Without a previous screen to draw on
+        let payload = window(0, 0, 8, 1, &[0]);
+        assert_eq!(decode_screen(&[], &payload, WIDTH, HEIGHT), None);
+    }
+
+    #[test]
+    fn refuses_unknown_payloads() {
+        assert_eq!(decode_screen(&[0xFF; 8], &[], WIDTH, HEIGHT), None);
+        assert_eq!(decode_screen(&[0xFF; 8], b"R", WIDTH, HEIGHT), None);
+        assert_eq!(decode_screen(&[0xFF; 8], b"X123", WIDTH, HEIGHT), None);
+    }
+
+    #[test]
+    fn reports_link_rates_and_starts_a_new_window() {
+        let mut counter = LinkCounter {
+            bytes: 3000,
+            frames: 4,
+            latency: Duration::from_millis(40),
+            wrong_length_frames: 1,
+            resyncs: 2,
+        };
+        let stats = counter.report(Duration::from_secs(2));
+        assert_eq!(stats.bytes_per_sec, 1500.0);
+        assert_eq!(stats.frames_per_sec, 2.0);
+        assert!((stats.decode_latency_ms - 10.0).abs() < 0.01);
+        assert_eq!((stats.wrong_length_frames, stats.resyncs), (1, 2));
+        // Rates start over, error counts add up for the whole connection
+        counter.bytes += 100;
+        counter.resyncs += 1;
+        let stats = counter.report(Duration::from_secs(1));
+        assert_eq!(stats.bytes_per_sec, 100.0);
+        assert_eq!(stats.frames_per_sec, 0.0);
+        assert_eq!(stats.decode_latency_ms, 0.0);
+        assert_eq!((stats.wrong_length_frames, stats.resyncs), (1, 3));
+    }
+
+    #[test]
+    fn reports_without_dividing_by_zero() {
+        let mut counter = LinkCounter {
+            bytes: 5,
+            ..Default::default()
+        };
+        assert!(counter.report(Duration::ZERO).bytes_per_sec.is_finite());
     }
 }
@@ -0,0 +1,451 @@
+// Logging
+use log::{debug, error, info};
+
+// Gui
+use eframe::egui;
+use egui::{TextureHandle, TextureOptions, Vec2};
+use image::RgbaImage;
+
+// Network
+use message_io::network::ToRemoteAddr;
+
+// Threads
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+// Other
+use crate::api::{LinkStats, SendToGui, SendToSerial, DEFAULT_DEVICE};
+use crate::logs::{looks_like_screen, LogView};
+use crate::profile::Profile;
+use crate::remote::run_remote;
+use crate::{bitmap, screen_coordinates, serial};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+// What the layout sliders allow, saved settings are clamped to them too
+pub const COLUMNS: RangeInclusive<usize> = 1..=4;
+pub const LOG_HEIGHT: RangeInclusive<f32> = 80.0..=600.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Local(String),
+    // Server address and device name
+    Remote(String, String),
+}
+
+// One watch of the dashboard with its own backend
+pub struct Watch {
+    pub name: String,
+    pub source: Source,
+    tx_serial: Sender<SendToSerial>,
+    rx_gui: Receiver<SendToGui>,
+    texture: Option<TextureHandle>,
+    logs: LogView,
+    stats: Option<LinkStats>,
+    // Other watches hosted by the same server
+    server_devices: Vec<String>,
+    // Follows what the backend reports, servers pick their own
+    profile: Profile,
+}
+
+impl Watch {
+    pub fn connect(
+        ctx: &egui::Context,
+        source: Source,
+        port: &str,
+        baud_rate: usize,
+        profile: &Profile,
+        log_dir: Option<&Path>,
+    ) -> Option<Self> {
+        let (tx_backend, rx_backend) = channel();
+        let (tx_serial, rx_serial) = channel();
+        let name = match &source {
+            Source::Local(port) => {
+                thread::spawn(move || {
+                    serial::main(tx_backend, rx_serial);
+                });
+                port.clone()
+            }
+            Source::Remote(address, device) => {
+                let remote_addr = match address.to_remote_addr() {
+                    Ok(x) => x,
+                    Err(x) => {
+                        error!("Invalid server address {}: {}", address, x);
+                        return None;
+                    }
+                };
+                let device_clone = device.clone();
+                thread::spawn(move || {
+                    run_remote(remote_addr, device_clone, tx_backend, rx_serial);
+                });
+                format!("{}@{}", device, address)
+            }
+        };
+        if *profile != Profile::default() {
+            let _ = tx_serial.send(SendToSerial::SetProfile(profile.clone()));
+        }
+        // Servers started with --serial ignore this
+        if !port.is_empty() {
+            let _ = tx_serial.send(SendToSerial::SelectPort(port.to_string(), baud_rate));
+        }
+
+        // Same as the relay in main(), repaint only when something arrives
+        let (tx_relay, rx_gui) = channel();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            for message in rx_backend {
+                if tx_relay.send(message).is_err() {
+                    break;
+                }
+                ctx.request_repaint();
+            }
+        });
+
+        let mut logs = LogView::default();
+        if let Err(x) = logs.set_log_regex(&profile.log_regex) {
+            error!("{}", x);
+        }
+        if let Some(dir) = log_dir {
+            logs.write_to(dir, &name);
+        }
+
+        info!("Added {} to the dashboard", name);
+        Some(Self {
+            name,
+            source,
+            tx_serial,
+            rx_gui,
+            texture: None,
+            logs,
+            stats: None,
+            server_devices: Vec::new(),
+            profile: profile.clone(),
+        })
+    }
+
+    pub fn send(&self, message: SendToSerial) {
+        if self.tx_serial.send(message).is_err() {
+            error!("Failed to send message to {}", self.name);
+        }
+    }
+
+    fn poll(&mut self, ctx: &egui::Context) {
+        while let Ok(message) = self.rx_gui.try_recv() {
+            match message {
+                SendToGui::LogToShow(input) => {
+                    for line in self.logs.push(&input) {
+                        if looks_like_screen(&line) {
+                            debug!(
+                                "Screen leaked into the logs of {}, requesting an update",
+                                self.name
+                            );
+                            self.send(SendToSerial::SendMessage(
+                                self.profile.screen_command.clone(),
+                            ));
+                        }
+                    }
+                }
+                SendToGui::ShowFrame(width, height, bits) => {
+                    let img = bitmap::unpack(&bits, width as usize, height as usize);
+                    self.show_frame(ctx, image::DynamicImage::ImageRgb8(img).to_rgba8());
+                }
+                SendToGui::ShowPng(x) => match image::load_from_memory(&x) {
+                    Ok(decoded) => self.show_frame(ctx, decoded.to_rgba8()),
+                    Err(x) => error!("Failed to decode png from {}: {}", self.name, x),
+                },
+                SendToGui::Stats(x) => self.stats = Some(x),
+                SendToGui::Devices(x) => self.server_devices = x,
+                SendToGui::Profile(x) => {
+                    if let Err(err) = x.validate() {
+                        error!("Ignoring profile from {}: {}", self.name, err);
+                        continue;
+                    }
+                    if let Err(x) = self.logs.set_log_regex(&x.log_regex) {
+                        error!("{}", x);
+                    }
+                    self.profile = x;
+                }
+                SendToGui::Ports(_)
+                | SendToGui::ShowFrameRle(..)
+                | SendToGui::FlashProgress(..)
+                | SendToGui::FlashDone(_)
+                | SendToGui::Connected(_) => {}
+            }
+        }
+    }
+
+    fn show_frame(&mut self, ctx: &egui::Context, rgba: RgbaImage) {
+        let color_image = egui::ColorImage::from_rgba_unmultiplied(
+            [rgba.width() as usize, rgba.height() as usize],
+            rgba.as_flat_samples().as_slice(),
+        );
+        self.texture = Some(ctx.load_texture(
+            format!("screen-{}", self.name),
+            color_image,
+            TextureOptions::NEAREST,
+        ));
+    }
+
+    // Returns false when the watch should be removed
+    fn show(&mut self, ui: &mut egui::Ui, log_height: f32) -> bool {
+        let mut keep = true;
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                ui.strong(&self.name);
+                if let Some(stats) = &self.stats {
+                    ui.label(format!(
+                        "{:.1} frames/s, {} resyncs",
+                        stats.frames_per_sec, stats.resyncs
+                    ));
+                }
+                if ui.small_button("Remove").clicked() {
+                    keep = false;
+                }
+            });
+            match &self.texture {
+                Some(texture) => {
+                    let response = ui.add(
+                        egui::Image::new(texture)
+                            .fit_to_exact_size(Vec2::new(200.0, 200.0))
+                            .sense(egui::Sense::click()),
+                    );
+                    if response.clicked() {
+                        if let Some(pos) = response.interact_pointer_pos() {
+                            let (x, y) = screen_coordinates(
+                                pos - response.rect.min,
+                                response.rect.size(),
+                                &self.profile,
+                            );
+                            self.send(SendToSerial::Touch(x, y));
+                        }
+                    }
+                }
+                None => {
+                    ui.allocate_ui(Vec2::new(200.0, 200.0), |ui| {
+                        ui.centered_and_justified(|ui| ui.label("Waiting for a frame"));
+                    });
+                }
+            }
+            let mut command = None;
+            for row in self.profile.buttons.chunks(4) {
+                ui.horizontal(|ui| {
+                    for button in row {
+                        if ui.small_button(&button.label).clicked() {
+                            command = Some(button.command.clone());
+                        }
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                if ui.small_button("Update screen").clicked() {
+                    command = Some(self.profile.screen_command.clone());
+                }
+                if ui.small_button("Reset").clicked() {
+                    command = Some(self.profile.reset_command.clone());
+                }
+            });
+            ui.allocate_ui(Vec2::new(400.0, log_height), |ui| {
+                self.logs.show(ui);
+            });
+            if let Some(command) = command {
+                self.send(SendToSerial::SendMessage(command));
+            }
+        });
+        keep
+    }
+}
+
+pub struct Dashboard {
+    pub open: bool,
+    watches: Vec<Watch>,
+    pub columns: usize,
+    pub log_height: f32,
+    // Add form
+    local_port: String,
+    remote_address: String,
+    remote_device: String,
+    baud_rate: String,
+    // Every watch writes its own log files there
+    pub log_dir: Option<PathBuf>,
+}
+
+impl Dashboard {
+    pub fn new(baud_rate: String) -> Self {
+        Self {
+            open: false,
+            watches: Vec::new(),
+            columns: 2,
+            log_height: 200.0,
+            local_port: String::new(),
+            remote_address: String::from(":24377"),
+            remote_device: String::from(DEFAULT_DEVICE),
+            baud_rate,
+            log_dir: None,
+        }
+    }
+
+    fn add(&mut self, ctx: &egui::Context, source: Source, port: &str, profile: &Profile) {
+        if self.watches.iter().any(|x| x.source == source) {
+            error!("{:?} is already on the dashboard", source);
+            return;
+        }
+        let Ok(baud_rate) = self.baud_rate.parse() else {
+            error!("Invalid baud rate {}", self.baud_rate);
+            return;
+        };
+        if let Some(watch) = Watch::connect(
+            ctx,
+            source,
+            port,
+            baud_rate,
+            profile,
+            self.log_dir.as_deref(),
+        ) {
+            self.watches.push(watch);
+        }
+    }
+
+    // Presses the button with this label on every watch that has one, the
+    // command depends on the profile of each watch
+    fn broadcast_button(&self, label: &str) {
+        for watch in &self.watches {
+            match watch.profile.buttons.iter().find(|x| x.label == label) {
+                Some(button) => watch.send(SendToSerial::SendMessage(button.command.clone())),
+                None => debug!("{} has no {} button", watch.name, label),
+            }
+        }
+    }
+
+    // Sends the same message to every watch
+    pub fn broadcast(&self, message: SendToSerial) {
+        debug!(
+            "Broadcasting {:?} to {} watches",
+            message,
+            self.watches.len()
+        );
+        for watch in &self.watches {
+            watch.send(message.clone());
+        }
+    }
+
+    // Every frame, also while the window is closed, so the channels don't pile up
+    pub fn poll(&mut self, ctx: &egui::Context) {
+        for watch in self.watches.iter_mut() {
+            watch.poll(ctx);
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, profile: &Profile) {
+        let mut open = self.open;
+        egui::Window::new("Dashboard")
+            .open(&mut open)
+            .default_size([1000.0, 700.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Baud rate:");
+                    ui.add(egui::TextEdit::singleline(&mut self.baud_rate).desired_width(80.0));
+                    ui.label("Port:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.local_port)
+                            .hint_text("/dev/ttyUSB0")
+                            .desired_width(120.0),
+                    );
+                    if ui.button("Add local").clicked() && !self.local_port.is_empty() {
+                        let port = self.local_port.clone();
+                        self.add(ctx, Source::Local(port.clone()), &port, profile);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Remote address:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.remote_address).desired_width(140.0),
+                    );
+                    ui.label("Device:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.remote_device).desired_width(100.0),
+                    );
+                    if ui.button("Add remote").clicked() {
+                        let source =
+                            Source::Remote(self.remote_address.clone(), self.remote_device.clone());
+                        // The port field is optional here, servers with --serial have their own
+                        let port = self.local_port.clone();
+                        self.add(ctx, source, &port, profile);
+                    }
+                });
+
+                // Devices of known servers that are not on the dashboard yet
+                let mut missing: Vec<Source> = Vec::new();
+                for watch in &self.watches {
+                    if let Source::Remote(address, _) = &watch.source {
+                        for device in &watch.server_devices {
+                            let source = Source::Remote(address.clone(), device.clone());
+                            if !missing.contains(&source)
+                                && !self.watches.iter().any(|x| x.source == source)
+                            {
+                                missing.push(source);
+                            }
+                        }
+                    }
+                }
+                if !missing.is_empty() {
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("Also on the server:");
+                        for source in missing {
+                            if let Source::Remote(address, device) = &source {
+                                if ui.small_button(format!("{}@{}", device, address)).clicked() {
+                                    self.add(ctx, source.clone(), "", profile);
+                                }
+                            }
+                        }
+                    });
+                }
+
+                ui.horizontal(|ui| {
+                    ui.add(egui::Slider::new(&mut self.columns, COLUMNS).text("Columns"));
+                    ui.add(egui::Slider::new(&mut self.log_height, LOG_HEIGHT).text("Log height"));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("All watches:");
+                    for button in &profile.buttons {
+                        if ui.small_button(&button.label).clicked() {
+                            self.broadcast_button(&button.label);
+                        }
+                    }
+                    if ui.small_button("Update screen").clicked() {
+                        for watch in &self.watches {
+                            watch.send(SendToSerial::SendMessage(
+                                watch.profile.screen_command.clone(),
+                            ));
+                        }
+                    }
+                });
+                ui.separator();
+
+                let mut removed: Option<usize> = None;
+                egui::ScrollArea::both().auto_shrink(false).show(ui, |ui| {
+                    egui::Grid::new("dashboard_grid")
+                        .spacing(Vec2::new(16.0, 16.0))
+                        .show(ui, |ui| {
+                            for (i, watch) in self.watches.iter_mut().enumerate() {
+                                // Every log view has its own scroll and filter state
+                                ui.push_id(i, |ui| {
+                                    if !watch.show(ui, self.log_height) {
+                                        removed = Some(i);
+                                    }
+                                });
+                                if (i + 1) % self.columns == 0 {
+                                    ui.end_row();
+                                }
+                            }
+                        });
+                });
+                if let Some(i) = removed {
+                    // Dropping the channels stops the backend threads
+                    let watch = self.watches.remove(i);
+                    info!("Removed {} from the dashboard", watch.name);
+                }
+            });
+        self.open = open;
+    }
+}
@@ -0,0 +1,290 @@
+// Logging
+use log::{debug, error, info};
+
+// Network
+use message_io::network::ToRemoteAddr;
+
+// Threads
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Arguments
+use clap::{Args, Subcommand};
+
+// Other
+use crate::api::{SendToGui, SendToSerial, DEFAULT_DEVICE};
+use crate::bitmap;
+use crate::flash;
+use crate::profile::Profile;
+use crate::remote::run_remote;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct HeadlessArgs {
+    #[arg(short, long, help = "ws-serial-tcp server, host:port")]
+    server: String,
+    #[arg(short, long, help = "Watch on the server", default_value_t = DEFAULT_DEVICE.to_string())]
+    device: String,
+    #[arg(short, long, help = "Serial port on the server")]
+    port: Option<String>,
+    #[arg(short, long, default_value_t = 921600)]
+    baudrate: usize,
+    #[arg(
+        long,
+        help = "Firmware profile to set on the server, a name from profiles/ or a path. It changes the watch for every client, without it the server keeps its own"
+    )]
+    profile: Option<String>,
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Print the watches hosted by the server, one per line
+    Devices,
+    /// Print the serial devices of the server, one per line
+    Ports,
+    /// Print the watch logs to stdout until interrupted
+    Logs,
+    /// Send a command to the watch, like menu-button:
+    Send { message: String },
+    /// Write a firmware image through the ROM bootloader and reboot the watch
+    Flash {
+        image: PathBuf,
+        #[arg(short, long, default_value = "0x10000", help = "Flash offset")]
+        offset: String,
+    },
+    /// Save the next frame, the format comes from the file extension
+    Screenshot {
+        output: PathBuf,
+        #[arg(
+            short,
+            long,
+            default_value_t = 10,
+            help = "Seconds to wait for a frame"
+        )]
+        timeout: u64,
+    },
+}
+
+fn wait_for<T>(
+    rx_gui: &Receiver<SendToGui>,
+    timeout: Duration,
+    mut filter: impl FnMut(SendToGui) -> Option<T>,
+) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match rx_gui.recv_timeout(left) {
+            Ok(message) => {
+                if let Some(x) = filter(message) {
+                    return Some(x);
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+// The remote backend returns once everything sent to it is written to the server,
+// it drops its end of rx_gui then
+fn wait_until_sent(
+    args: &HeadlessArgs,
+    rx_gui: &Receiver<SendToGui>,
+    tx_serial: Sender<SendToSerial>,
+) {
+    drop(tx_serial);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match rx_gui.recv_timeout(left) {
+            Ok(SendToGui::Connected(false)) => break,
+            Ok(_) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => break,
+        }
+    }
+    error!("Failed to send to {}", args.server);
+    std::process::exit(1);
+}
+
+// Servers started with --serial already opened the port, the server ignores this then
+fn select_port(args: &HeadlessArgs, tx_serial: &Sender<SendToSerial>) {
+    if let Some(port) = &args.port {
+        let _ = tx_serial.send(SendToSerial::SelectPort(port.clone(), args.baudrate));
+    }
+}
+
+// Exits the process with 0 on success, 1 on failure and 2 on bad arguments
+pub fn run(args: HeadlessArgs) {
+    let remote_addr = match args.server.to_remote_addr() {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Invalid server address {}: {}", args.server, x);
+            std::process::exit(2);
+        }
+    };
+    let (tx_gui, rx_gui) = channel();
+    let (tx_serial, rx_serial) = channel();
+    let device = args.device.clone();
+    thread::spawn(move || {
+        run_remote(remote_addr, device, tx_gui, rx_serial);
+    });
+
+    let mut profile = Profile::default();
+    if let Some(name) = &args.profile {
+        profile = match Profile::find(name) {
+            Ok(x) => x,
+            Err(x) => {
+                error!("{}", x);
+                std::process::exit(2);
+            }
+        };
+        let _ = tx_serial.send(SendToSerial::SetProfile(profile.clone()));
+    }
+
+    match &args.action {
+        Action::Devices => {
+            // The remote backend asks for them once connected
+            let devices = wait_for(&rx_gui, Duration::from_secs(5), |message| match message {
+                SendToGui::Devices(x) => Some(x),
+                _ => None,
+            });
+            match devices {
+                Some(devices) => {
+                    for device in devices {
+                        println!("{}", device);
+                    }
+                }
+                None => {
+                    error!("Server did not answer with devices");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Action::Ports => {
+            let _ = tx_serial.send(SendToSerial::AskForPorts());
+            let ports = wait_for(&rx_gui, Duration::from_secs(5), |message| match message {
+                SendToGui::Ports(x) => Some(x),
+                _ => None,
+            });
+            match ports {
+                Some(ports) => {
+                    for port in ports.iter().filter(|x| *x != "None") {
+                        println!("{}", port);
+                    }
+                }
+                None => {
+                    error!("Server did not answer with ports");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Action::Logs => {
+            select_port(&args, &tx_serial);
+            for message in rx_gui {
+                if let SendToGui::LogToShow(logs) = message {
+                    print!("{}", logs);
+                    let _ = std::io::stdout().flush();
+                }
+            }
+            error!("Connection to server lost");
+            std::process::exit(1);
+        }
+        Action::Send { message } => {
+            select_port(&args, &tx_serial);
+            let _ = tx_serial.send(SendToSerial::SendMessage(message.clone()));
+            wait_until_sent(&args, &rx_gui, tx_serial);
+        }
+        Action::Flash { image, offset } => {
+            let Some(offset) = flash::parse_offset(offset) else {
+                error!("Invalid flash offset {}", offset);
+                std::process::exit(2);
+            };
+            let image = match std::fs::read(image) {
+                Ok(x) => x,
+                Err(x) => {
+                    error!("Failed to read {}: {}", image.display(), x);
+                    std::process::exit(2);
+                }
+            };
+            select_port(&args, &tx_serial);
+            let _ = tx_serial.send(SendToSerial::Flash(offset, image));
+            let mut last_percent = None;
+            let res = wait_for(&rx_gui, Duration::from_secs(600), |message| match message {
+                SendToGui::FlashProgress(written, total) => {
+                    let percent = written * 100 / total.max(1);
+                    if last_percent != Some(percent / 10) {
+                        last_percent = Some(percent / 10);
+                        info!("Flashed {} / {} bytes", written, total);
+                    }
+                    None
+                }
+                SendToGui::FlashDone(x) => Some(x),
+                _ => None,
+            });
+            match res {
+                Some(Ok(())) => info!("Flashing done"),
+                Some(Err(x)) => {
+                    error!("Flashing failed: {}", x);
+                    std::process::exit(1);
+                }
+                None => {
+                    error!("No answer from the server");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Action::Screenshot { output, timeout } => {
+            select_port(&args, &tx_serial);
+            let deadline = Instant::now() + Duration::from_secs(*timeout);
+            // The server replays the profile and the last frame of the watch when the
+            // remote backend attaches and answers its AskForDevices after that, the
+            // screen command comes from that profile and later frames are new
+            let attached = wait_for(
+                &rx_gui,
+                deadline.saturating_duration_since(Instant::now()),
+                |message| match message {
+                    SendToGui::Profile(x) if x.validate().is_ok() => {
+                        profile = x;
+                        None
+                    }
+                    SendToGui::Devices(_) => Some(()),
+                    _ => None,
+                },
+            );
+            if attached.is_none() {
+                error!("Server did not answer in {} seconds", timeout);
+                std::process::exit(1);
+            }
+            let _ = tx_serial.send(SendToSerial::SendMessage(profile.screen_command.clone()));
+            let frame = wait_for(
+                &rx_gui,
+                deadline.saturating_duration_since(Instant::now()),
+                |message| match message {
+                    SendToGui::ShowFrame(width, height, bits) => {
+                        Some(image::DynamicImage::ImageRgb8(bitmap::unpack(
+                            &bits,
+                            width as usize,
+                            height as usize,
+                        )))
+                    }
+                    SendToGui::ShowPng(png) => image::load_from_memory(&png).ok(),
+                    _ => None,
+                },
+            );
+            let Some(frame) = frame else {
+                error!("No frame received in {} seconds", timeout);
+                std::process::exit(1);
+            };
+            if let Err(x) = frame.save(output) {
+                error!("Failed to save frame to {}: {}", output.display(), x);
+                std::process::exit(1);
+            }
+            info!("Saved frame to {}", output.display());
+        }
+    }
+    debug!("Headless action done");
+    std::process::exit(0);
+}
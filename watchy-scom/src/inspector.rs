@@ -0,0 +1,138 @@
+// Logging
+use log::debug;
+
+// Gui
+use eframe::egui;
+use egui::{Color32, Pos2, Rect, Sense, Stroke, TextureHandle, Vec2};
+use image::RgbaImage;
+
+pub struct Inspector {
+    pub open: bool,
+    zoom: f32,
+    offset: Vec2,
+    grid: bool,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self {
+            open: false,
+            zoom: 4.0,
+            offset: Vec2::ZERO,
+            grid: true,
+        }
+    }
+}
+
+impl Inspector {
+    pub fn show(&mut self, ctx: &egui::Context, texture: &TextureHandle, frame: &RgbaImage) {
+        let mut open = self.open;
+        egui::Window::new("Pixel inspector")
+            .open(&mut open)
+            .default_size([600.0, 600.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.add(egui::Slider::new(&mut self.zoom, 1.0..=32.0).text("Zoom"));
+                    ui.checkbox(&mut self.grid, "Grid");
+                    if ui.button("Reset view").clicked() {
+                        self.zoom = 4.0;
+                        self.offset = Vec2::ZERO;
+                    }
+                });
+
+                let mut hovered: Option<(u32, u32)> = None;
+                let (response, painter) = ui.allocate_painter(
+                    Vec2::new(ui.available_width(), ui.available_height() - 20.0),
+                    Sense::drag(),
+                );
+                if response.dragged() {
+                    self.offset += response.drag_delta();
+                }
+
+                let image_size = Vec2::new(frame.width() as f32, frame.height() as f32);
+                if let Some(pointer) = response.hover_pos() {
+                    let scroll = ui.input(|i| i.raw_scroll_delta.y);
+                    if scroll != 0.0 {
+                        // Zoom around the cursor so the pixel under it stays in place
+                        let before = (pointer - response.rect.min - self.offset) / self.zoom;
+                        self.zoom = (self.zoom * (1.0 + scroll / 200.0)).clamp(1.0, 32.0);
+                        self.offset = pointer - response.rect.min - before * self.zoom;
+                    }
+                }
+
+                let image_rect =
+                    Rect::from_min_size(response.rect.min + self.offset, image_size * self.zoom);
+                let painter = painter.with_clip_rect(response.rect);
+                painter.rect_filled(response.rect, 0.0, Color32::DARK_GRAY);
+                painter.image(
+                    texture.id(),
+                    image_rect,
+                    Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+                    Color32::WHITE,
+                );
+
+                if self.grid && self.zoom >= 4.0 {
+                    let stroke = Stroke::new(1.0, Color32::from_gray(128));
+                    for x in 0..=frame.width() {
+                        let pos_x = image_rect.min.x + x as f32 * self.zoom;
+                        painter.line_segment(
+                            [
+                                Pos2::new(pos_x, image_rect.min.y),
+                                Pos2::new(pos_x, image_rect.max.y),
+                            ],
+                            stroke,
+                        );
+                    }
+                    for y in 0..=frame.height() {
+                        let pos_y = image_rect.min.y + y as f32 * self.zoom;
+                        painter.line_segment(
+                            [
+                                Pos2::new(image_rect.min.x, pos_y),
+                                Pos2::new(image_rect.max.x, pos_y),
+                            ],
+                            stroke,
+                        );
+                    }
+                }
+
+                if let Some(pointer) = response.hover_pos() {
+                    if image_rect.contains(pointer) {
+                        let pixel = (pointer - image_rect.min) / self.zoom;
+                        let (x, y) = (pixel.x as u32, pixel.y as u32);
+                        if x < frame.width() && y < frame.height() {
+                            hovered = Some((x, y));
+                            let cell = Rect::from_min_size(
+                                image_rect.min + Vec2::new(x as f32, y as f32) * self.zoom,
+                                Vec2::splat(self.zoom),
+                            );
+                            painter.rect_stroke(cell, 0.0, Stroke::new(1.0, Color32::RED));
+                        }
+                    }
+                }
+
+                match hovered {
+                    Some((x, y)) => {
+                        let bit = pixel_bit(frame, x, y);
+                        ui.label(format!("x: {} y: {} bit: {}", x, y, bit));
+                    }
+                    None => {
+                        ui.label("Hover the screen to inspect pixels");
+                    }
+                }
+            });
+        if self.open && !open {
+            debug!("Closing pixel inspector");
+        }
+        self.open = open;
+    }
+}
+
+// The watch stores white as 1 and black as 0
+pub fn pixel_bit(frame: &RgbaImage, x: u32, y: u32) -> u8 {
+    if frame.get_pixel(x, y)[0] > 127 {
+        1
+    } else {
+        0
+    }
+}
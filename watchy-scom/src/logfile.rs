@@ -0,0 +1,182 @@
+// Every log line also goes to disk, the log view only keeps the latest ones.
+// Each session writes <dir>/<name>-<start time>-<n>.log, a new file starts every
+// MAX_FILE_SIZE bytes and only the newest KEEP_FILES files of a name are kept.
+
+// Logging
+use log::{debug, error, info};
+
+// Other
+use chrono::{DateTime, Local, NaiveDateTime};
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+const KEEP_FILES: usize = 50;
+
+pub const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+const SESSION_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+const SESSION_FORMAT_LEN: usize = 19;
+
+// The time and the line of an entry written by LogFile::write
+pub fn parse_entry(entry: &str) -> Option<(DateTime<Local>, &str)> {
+    let (time, line) = entry.strip_prefix('[')?.split_once("] ")?;
+    let time = NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?;
+    Some((time.and_local_timezone(Local).earliest()?, line))
+}
+
+pub struct LogFile {
+    dir: PathBuf,
+    name: String,
+    session: String,
+    index: usize,
+    // Files of this session, oldest first
+    paths: Vec<PathBuf>,
+    file: Option<LineWriter<File>>,
+    written: u64,
+    // Stops after the first error instead of failing on every line
+    failed: bool,
+}
+
+impl LogFile {
+    // Nothing is created until the first line arrives
+    pub fn new(dir: &Path, name: &str) -> Self {
+        // Port paths like /dev/ttyUSB0 become plain file names
+        let name: String = name
+            .chars()
+            .map(|x| {
+                if x.is_ascii_alphanumeric() || x == '_' {
+                    x
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        Self {
+            dir: dir.to_path_buf(),
+            name: name.trim_matches('-').to_string(),
+            session: Local::now().format(SESSION_FORMAT).to_string(),
+            index: 0,
+            paths: Vec::new(),
+            file: None,
+            written: 0,
+            failed: false,
+        }
+    }
+
+    pub fn write(&mut self, time: &DateTime<Local>, line: &str) {
+        if self.failed {
+            return;
+        }
+        if self.file.is_none() || self.written >= MAX_FILE_SIZE {
+            if let Err(x) = self.rotate() {
+                error!("Failed to open a log file in {}: {}", self.dir.display(), x);
+                self.failed = true;
+                return;
+            }
+        }
+        let entry = format!("[{}] {}\n", time.format(TIME_FORMAT), line);
+        match self.file.as_mut().unwrap().write_all(entry.as_bytes()) {
+            Ok(()) => self.written += entry.len() as u64,
+            Err(x) => {
+                error!("Failed to write log file: {}", x);
+                self.failed = true;
+            }
+        }
+    }
+
+    // Old ones may be pruned already
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Sessions started in the same second share a name, never overwrite
+        // the files of another one
+        let (path, file) = loop {
+            self.index += 1;
+            let path = self
+                .dir
+                .join(format!("{}-{}-{}.log", self.name, self.session, self.index));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(x) if x.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(x) => return Err(x),
+            }
+        };
+        info!("Writing logs to {}", path.display());
+        self.paths.push(path);
+        self.file = Some(LineWriter::new(file));
+        self.written = 0;
+        self.prune();
+        Ok(())
+    }
+
+    // Oldest first by modification time
+    fn prune(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let prefix = format!("{}-", self.name);
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| {
+                // Followed by the session time, so "watch" leaves "watch-2" alone
+                let name = x.file_name().unwrap_or_default().to_string_lossy();
+                x.extension().is_some_and(|x| x == "log")
+                    && name
+                        .strip_prefix(&prefix)
+                        .and_then(|x| x.get(..SESSION_FORMAT_LEN))
+                        .is_some_and(|x| NaiveDateTime::parse_from_str(x, SESSION_FORMAT).is_ok())
+            })
+            .collect();
+        if files.len() <= KEEP_FILES {
+            return;
+        }
+        files.sort_by_key(|x| x.metadata().and_then(|x| x.modified()).ok());
+        for path in &files[..files.len() - KEEP_FILES] {
+            debug!("Removing old log file {}", path.display());
+            if let Err(x) = fs::remove_file(path) {
+                error!("Failed to remove {}: {}", path.display(), x);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_in_one_second_keep_their_files() {
+        let dir = std::env::temp_dir().join(format!("logfile-test-{}", std::process::id()));
+        let now = Local::now();
+        let mut first = LogFile::new(&dir, "watch");
+        let mut second = LogFile::new(&dir, "watch");
+        first.write(&now, "first");
+        second.write(&now, "second");
+        drop((first, second));
+        let mut contents: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| fs::read_to_string(x.unwrap().path()).unwrap())
+            .collect();
+        contents.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents.len(), 2);
+        assert!(contents[0].ends_with("] first\n"));
+        assert!(contents[1].ends_with("] second\n"));
+    }
+
+    #[test]
+    fn parses_written_entries() {
+        let time = Local::now();
+        let entry = format!("[{}] src/a.cpp:1: x] y", time.format(TIME_FORMAT));
+        let (parsed, line) = parse_entry(&entry).unwrap();
+        assert_eq!(parsed.timestamp_millis(), time.timestamp_millis());
+        assert_eq!(line, "src/a.cpp:1: x] y");
+        assert!(parse_entry("no time").is_none());
+        assert!(parse_entry("[yesterday] line").is_none());
+    }
+}
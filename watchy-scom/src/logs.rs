@@ -0,0 +1,469 @@
+// Gui
+use eframe::egui;
+use egui::{Color32, RichText};
+
+// Other
+use crate::logfile::{self, LogFile, TIME_FORMAT};
+use crate::profile::Profile;
+use chrono::{DateTime, Local};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+// Lines kept for the view, older ones are only in the log files
+const MAX_LINES: usize = 5000;
+// A line without a newline for this long is shown anyway
+const MAX_PARTIAL: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 4] = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "Error",
+            LogLevel::Warn => "Warn",
+            LogLevel::Info => "Info",
+            LogLevel::Debug => "Debug",
+        }
+    }
+
+    pub fn color(&self) -> Color32 {
+        match self {
+            LogLevel::Error => Color32::LIGHT_RED,
+            LogLevel::Warn => Color32::YELLOW,
+            LogLevel::Info => Color32::LIGHT_BLUE,
+            LogLevel::Debug => Color32::WHITE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub time: DateTime<Local>,
+    pub file: String,
+    pub line: Option<u32>,
+    pub level: LogLevel,
+    pub message: String,
+    pub raw: String,
+}
+
+impl LogLine {
+    // Directory of the source file, or the ESP-IDF tag
+    pub fn module(&self) -> &str {
+        match self.file.rsplit_once('/') {
+            Some((module, _)) => module,
+            None => &self.file,
+        }
+    }
+}
+
+pub struct LogParser {
+    // From the profile, see profile.rs
+    firmware: Regex,
+    esp_idf: Regex,
+}
+
+impl Default for LogParser {
+    fn default() -> Self {
+        Self::new(&Profile::default().log_regex).unwrap()
+    }
+}
+
+impl LogParser {
+    pub fn new(log_regex: &str) -> Result<Self, String> {
+        let firmware =
+            Regex::new(log_regex).map_err(|x| format!("Invalid log regex {}: {}", log_regex, x))?;
+        Ok(Self {
+            firmware,
+            // E (1234) tag: message
+            esp_idf: Regex::new(r"^(?P<level>[EWIDV]) \(\d+\) (?P<tag>[^:]+): (?P<message>.*)$")
+                .unwrap(),
+        })
+    }
+
+    pub fn parse(&self, time: DateTime<Local>, raw: &str) -> Option<LogLine> {
+        if let Some(caps) = self.firmware.captures(raw) {
+            // Only file is required, the rest falls back to the whole line
+            let message = caps.name("message").map_or(raw, |x| x.as_str()).to_string();
+            let file = caps.name("file").map_or("", |x| x.as_str()).to_string();
+            return Some(LogLine {
+                time,
+                file,
+                line: caps.name("line").and_then(|x| x.as_str().parse().ok()),
+                level: guess_level(&message),
+                message,
+                raw: raw.to_string(),
+            });
+        }
+        if let Some(caps) = self.esp_idf.captures(raw) {
+            let level = match &caps["level"] {
+                "E" => LogLevel::Error,
+                "W" => LogLevel::Warn,
+                "I" => LogLevel::Info,
+                _ => LogLevel::Debug,
+            };
+            return Some(LogLine {
+                time,
+                file: caps["tag"].to_string(),
+                line: None,
+                level,
+                message: caps["message"].to_string(),
+                raw: raw.to_string(),
+            });
+        }
+        None
+    }
+}
+
+// Screen bytes that ended up in the logs, the frame markers were probably missed
+pub fn looks_like_screen(line: &str) -> bool {
+    line.chars().filter(|x| !x.is_ascii()).count() > 30
+}
+
+// InkWatchy logs everything through debugLog, so the level comes from the wording.
+// Other firmwares matched by the profile regex are treated the same
+fn guess_level(message: &str) -> LogLevel {
+    let lower = message.to_lowercase();
+    if lower.contains("error") || lower.contains("fail") {
+        LogLevel::Error
+    } else if lower.contains("warn") {
+        LogLevel::Warn
+    } else {
+        LogLevel::Debug
+    }
+}
+
+pub struct LogView {
+    parser: LogParser,
+    pub lines: VecDeque<LogLine>,
+    pub raw: VecDeque<(DateTime<Local>, String)>,
+    levels: [bool; 4],
+    module: String,
+    search: String,
+    show_raw: bool,
+    // End of the last chunk, the rest of its line comes with the next one
+    partial: String,
+    file: Option<LogFile>,
+    export_path: String,
+    export_status: String,
+}
+
+impl Default for LogView {
+    fn default() -> Self {
+        Self {
+            parser: LogParser::default(),
+            lines: VecDeque::new(),
+            raw: VecDeque::new(),
+            levels: [true; 4],
+            module: String::new(),
+            search: String::new(),
+            show_raw: false,
+            partial: String::new(),
+            file: None,
+            export_path: String::from("logs.txt"),
+            export_status: String::new(),
+        }
+    }
+}
+
+impl LogView {
+    // Later lines use the new format, the old ones stay as they were parsed
+    pub fn set_log_regex(&mut self, log_regex: &str) -> Result<(), String> {
+        self.parser = LogParser::new(log_regex)?;
+        Ok(())
+    }
+
+    // Every line pushed from now on is also written there
+    pub fn write_to(&mut self, dir: &Path, name: &str) {
+        self.file = Some(LogFile::new(dir, name));
+    }
+
+    // Returns the unrecognized lines, so the caller can look at them. Serial reads
+    // end anywhere, so only finished lines are taken
+    pub fn push(&mut self, input: &str) -> Vec<String> {
+        let time = Local::now();
+        let mut rejected = Vec::new();
+        self.partial.push_str(input);
+        let finished = match self.partial.rfind('\n') {
+            Some(end) => {
+                let rest = self.partial.split_off(end + 1);
+                std::mem::replace(&mut self.partial, rest)
+            }
+            None if self.partial.len() > MAX_PARTIAL => std::mem::take(&mut self.partial),
+            None => return rejected,
+        };
+        for raw in finished.lines() {
+            if raw.trim().is_empty() {
+                continue;
+            }
+            if let Some(file) = &mut self.file {
+                file.write(&time, raw);
+            }
+            match self.parser.parse(time, raw) {
+                Some(line) => {
+                    if self.lines.len() == MAX_LINES {
+                        self.lines.pop_front();
+                    }
+                    self.lines.push_back(line);
+                }
+                None => {
+                    if self.raw.len() == MAX_LINES {
+                        self.raw.pop_front();
+                    }
+                    self.raw.push_back((time, raw.to_string()));
+                    rejected.push(raw.to_string());
+                }
+            }
+        }
+        rejected
+    }
+
+    pub fn matches(&self, line: &LogLine) -> bool {
+        let level_index = LogLevel::ALL
+            .iter()
+            .position(|x| *x == line.level)
+            .unwrap_or(0);
+        self.levels[level_index]
+            && (self.module.is_empty() || line.module().contains(&self.module))
+            && (self.search.is_empty() || line.raw.contains(&self.search))
+    }
+
+    // What the current tab shows with its filters, with timestamps
+    fn shown_lines(&self) -> Vec<(DateTime<Local>, &str)> {
+        if self.show_raw {
+            self.raw
+                .iter()
+                .filter(|(_, x)| self.search.is_empty() || x.contains(&self.search))
+                .map(|(time, x)| (*time, x.as_str()))
+                .collect()
+        } else {
+            self.lines
+                .iter()
+                .filter(|x| self.matches(x))
+                .map(|x| (x.time, x.raw.as_str()))
+                .collect()
+        }
+    }
+
+    // Whether the current tab would show a line read back from the log files
+    fn shows(&self, time: DateTime<Local>, raw: &str) -> bool {
+        match self.parser.parse(time, raw) {
+            Some(line) => !self.show_raw && self.matches(&line),
+            None => self.show_raw && (self.search.is_empty() || raw.contains(&self.search)),
+        }
+    }
+
+    // Every line of the session the filters let through, from the log files. Without
+    // them only the lines still in the view are left
+    fn export(&self, path: &Path) -> std::io::Result<usize> {
+        let mut lines = Vec::new();
+        match &self.file {
+            Some(log_file) => {
+                for session_path in log_file.paths() {
+                    let text = match fs::read_to_string(session_path) {
+                        Ok(x) => x,
+                        Err(x) if x.kind() == ErrorKind::NotFound => continue,
+                        Err(x) => return Err(x),
+                    };
+                    lines.extend(
+                        text.lines()
+                            .filter_map(logfile::parse_entry)
+                            .filter(|(time, raw)| self.shows(*time, raw))
+                            .map(|(time, raw)| (time, raw.to_string())),
+                    );
+                }
+            }
+            None => lines.extend(
+                self.shown_lines()
+                    .into_iter()
+                    .map(|(time, raw)| (time, raw.to_string())),
+            ),
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        for (time, line) in &lines {
+            writeln!(file, "[{}] {}", time.format(TIME_FORMAT), line)?;
+        }
+        file.flush()?;
+        Ok(lines.len())
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.selectable_value(
+                &mut self.show_raw,
+                false,
+                format!("Logs ({})", self.lines.len()),
+            );
+            ui.selectable_value(
+                &mut self.show_raw,
+                true,
+                format!("Raw ({})", self.raw.len()),
+            );
+            if ui.button("Clear").clicked() {
+                self.lines.clear();
+                self.raw.clear();
+            }
+            let export = ui
+                .button("Export")
+                .on_hover_text("Every line of this session that passes the filters");
+            if export.clicked() {
+                let path = Path::new(&self.export_path);
+                self.export_status = match self.export(path) {
+                    Ok(x) => format!("Exported {} lines", x),
+                    Err(x) => format!("Failed to export to {}: {}", path.display(), x),
+                };
+            }
+            ui.add(egui::TextEdit::singleline(&mut self.export_path).desired_width(120.0));
+            ui.label(&self.export_status);
+        });
+        ui.horizontal(|ui| {
+            for (i, level) in LogLevel::ALL.iter().enumerate() {
+                ui.checkbox(&mut self.levels[i], level.name());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Module:");
+            ui.add(egui::TextEdit::singleline(&mut self.module).desired_width(120.0));
+            ui.label("Search:");
+            ui.add(egui::TextEdit::singleline(&mut self.search).desired_width(160.0));
+        });
+        ui.separator();
+
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        if self.show_raw {
+            let shown = self.shown_lines();
+            egui::ScrollArea::both()
+                .stick_to_bottom(true)
+                .auto_shrink(false)
+                .show_rows(ui, row_height, shown.len(), |ui, range| {
+                    for (_, line) in &shown[range] {
+                        ui.add(egui::Label::new(RichText::new(*line).monospace()).wrap(false));
+                    }
+                });
+        } else {
+            let shown: Vec<&LogLine> = self.lines.iter().filter(|x| self.matches(x)).collect();
+            egui::ScrollArea::both()
+                .stick_to_bottom(true)
+                .auto_shrink(false)
+                .show_rows(ui, row_height, shown.len(), |ui, range| {
+                    for line in &shown[range] {
+                        ui.add(
+                            egui::Label::new(
+                                RichText::new(&line.raw)
+                                    .monospace()
+                                    .color(line.level.color()),
+                            )
+                            .wrap(false),
+                        );
+                    }
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_inkwatchy_lines() {
+        let line = LogParser::default()
+            .parse(
+                Local::now(),
+                "src/hardware/rtc.cpp:45: Failed to read the time",
+            )
+            .unwrap();
+        assert_eq!(line.file, "src/hardware/rtc.cpp");
+        assert_eq!(line.module(), "src/hardware");
+        assert_eq!(line.line, Some(45));
+        assert_eq!(line.level, LogLevel::Error);
+        assert_eq!(line.message, "Failed to read the time");
+    }
+
+    #[test]
+    fn parses_esp_idf_lines() {
+        let line = LogParser::default()
+            .parse(Local::now(), "W (1234) wifi: Connection lost")
+            .unwrap();
+        assert_eq!(line.file, "wifi");
+        assert_eq!(line.module(), "wifi");
+        assert_eq!(line.line, None);
+        assert_eq!(line.level, LogLevel::Warn);
+        assert_eq!(line.message, "Connection lost");
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        let parser = LogParser::default();
+        for raw in [
+            "",
+            "ets Jun  8 2016 00:22:57",
+            "lib/rtc.cpp:45: x",
+            "X (12) tag: x",
+        ] {
+            assert!(parser.parse(Local::now(), raw).is_none(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn joins_lines_split_between_chunks() {
+        let mut view = LogView::default();
+        assert!(view.push("src/main.cpp:1: fir").is_empty());
+        assert!(view.lines.is_empty());
+        assert!(view.push("st\r\nsrc/main.cpp:2: second\nboot").is_empty());
+        let messages: Vec<&str> = view.lines.iter().map(|x| x.message.as_str()).collect();
+        assert_eq!(messages, ["first", "second"]);
+        assert_eq!(view.push("ing\n"), ["booting"]);
+    }
+
+    #[test]
+    fn exports_lines_gone_from_the_view() {
+        let dir = std::env::temp_dir().join(format!("logs-export-test-{}", std::process::id()));
+        let mut view = LogView::default();
+        view.write_to(&dir, "watch");
+        view.push("src/a.cpp:1: Wifi failed\nnot a log line\n");
+        for i in 0..MAX_LINES {
+            view.push(&format!("src/b.cpp:{}: tick\n", i));
+        }
+        assert!(view.lines.iter().all(|x| x.file == "src/b.cpp"));
+        // Only errors
+        view.levels = [true, false, false, false];
+        let output = dir.join("export.txt");
+        assert_eq!(view.export(&output).unwrap(), 1);
+        let exported = fs::read_to_string(&output).unwrap();
+        assert!(exported.ends_with("] src/a.cpp:1: Wifi failed\n"));
+        view.show_raw = true;
+        assert_eq!(view.export(&output).unwrap(), 1);
+        assert!(fs::read_to_string(&output)
+            .unwrap()
+            .ends_with("] not a log line\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exports_the_view_without_log_files() {
+        let dir = std::env::temp_dir().join(format!("logs-view-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut view = LogView::default();
+        view.push("src/a.cpp:1: one\nsrc/a.cpp:2: two\n");
+        view.search = "two".to_string();
+        let output = dir.join("export.txt");
+        assert_eq!(view.export(&output).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
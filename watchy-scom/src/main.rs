@@ -2,60 +2,155 @@
 
 pub mod api;
 pub mod serial;
+pub mod bitmap;
+pub mod codec;
+pub mod discovery;
+pub mod parser;
+pub mod flash;
+pub mod profile;
 pub mod remote;
+pub mod inspector;
+pub mod screenshot;
+pub mod logs;
+pub mod logfile;
+pub mod headless;
+pub mod dashboard;
+pub mod terminal;
+pub mod settings;
 
 use crate::SendToSerial::*;
-use api::{SendToGui, SendToSerial};
+use api::{LinkStats, SendToGui, SendToSerial};
 use eframe::egui;
-use egui::{Color32, Vec2};
-use egui_extras::RetainedImage;
-use log::{debug, error};
-use message_io::network::{RemoteAddr, ToRemoteAddr};
-use regex::Regex;
+use egui::{TextureHandle, TextureOptions, Vec2};
+use image::RgbaImage;
+use headless::HeadlessArgs;
+use inspector::Inspector;
+use dashboard::Dashboard;
+use discovery::Server;
+use profile::Profile;
+use logs::{looks_like_screen, LogView};
+use screenshot::{ScreenshotFormat, ScreenshotSettings};
+use terminal::Terminal;
+use settings::{Backend, Settings};
+use log::{debug, error, info};
+use message_io::network::ToRemoteAddr;
 use remote::run_remote;
 use std::process::Command;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
-use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
-    #[arg(short, long)]
+    #[arg(short, long, help = "Serial port to open on start, on the server with --remote")]
     port: Option<String>,
+    #[arg(short, long, help = "Connect to a ws-serial-tcp server on start, host:port")]
+    remote: Option<String>,
+    #[arg(short, long, requires = "remote", help = "Watch on the --remote server")]
+    device: Option<String>,
     #[arg(
         short,
         long
     )]
     baudrate: Option<i32>,
+    #[arg(
+        short,
+        long,
+        help = "Command used by \"Open screen\" to view the saved png",
+        default_value = "xdg-open"
+    )]
+    viewer: String,
+    #[arg(
+        short,
+        long,
+        help = "Directory where screenshots are saved",
+        default_value = "screenshots"
+    )]
+    screenshot_dir: String,
+    #[arg(long, help = "Firmware profile, a name from profiles/ or a path to one")]
+    profile: Option<String>,
+    #[arg(
+        long,
+        help = "Directory for the log files of every session",
+        default_value = "logs"
+    )]
+    log_dir: String,
+    #[arg(long, help = "Only keep the logs in memory")]
+    no_log_files: bool,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Talk to a ws-serial-tcp server from the shell, without the gui
+    Headless(HeadlessArgs),
 }
 
 fn main() -> Result<(), eframe::Error> {
     let args = Args::parse();
 
-    let (tx_gui, rx_gui) = channel();
-
     env_logger::init();
+
+    if let Some(Mode::Headless(headless_args)) = args.mode {
+        headless::run(headless_args);
+    }
+
+    let (tx_gui, rx_backend) = channel();
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([1200.0, 650.0]),
         ..Default::default()
     };
 
-    let mut baudrate = String::from("921600");
-    if let Some(baud_rate_args) = args.baudrate {
-        baudrate = format!("{}", baud_rate_args);
-    }
-
     eframe::run_native(
         "watchy-scom",
         options,
-        Box::new(|cc| {
+        Box::new(move |cc| {
             // This gives us image support:
             egui_extras::install_image_loaders(&cc.egui_ctx);
 
-            Box::new(MyApp::new(tx_gui, rx_gui, args.port, baudrate))
+            // Arguments win over what was saved last time
+            let mut settings = Settings::load(cc.storage);
+            if let Some(remote) = args.remote {
+                settings.backend = Some(Backend::Remote);
+                settings.remote_address = remote;
+                if let Some(device) = args.device {
+                    settings.remote_device = device;
+                }
+                // Servers started with --serial don't need one
+                settings.port = args.port;
+            } else if let Some(port) = args.port {
+                settings.backend = Some(Backend::Local);
+                settings.port = Some(port);
+            }
+            if let Some(baud_rate_args) = args.baudrate {
+                settings.baud_rate = format!("{}", baud_rate_args);
+            }
+            if args.profile.is_some() {
+                settings.profile = args.profile;
+            }
+
+            // Wakes the gui only when a backend has something to show
+            let (tx_relay, rx_gui) = channel();
+            let ctx = cc.egui_ctx.clone();
+            thread::spawn(move || {
+                for message in rx_backend {
+                    if tx_relay.send(message).is_err() {
+                        break;
+                    }
+                    ctx.request_repaint();
+                }
+            });
+
+            let mut app = MyApp::new(tx_gui, rx_gui, settings, args.viewer, args.screenshot_dir);
+            app.start_discovery(&cc.egui_ctx);
+            if !args.no_log_files {
+                app.write_logs(std::path::Path::new(&args.log_dir));
+            }
+            Box::new(app)
         }),
     )
 }
@@ -65,35 +160,186 @@ struct MyApp {
     tx_gui: Sender<SendToGui>,
     rx_gui: Receiver<SendToGui>,
     sel_port: usize,
-    port: Option<String>, // Argument overwrite
+    port: Option<String>, // Last connected, from the arguments or the settings
     ports: Vec<String>,
     baud_rate: String,
-    image: Vec<u8>,
-    logs: String,
+    texture: Option<TextureHandle>,
+    frame: Option<RgbaImage>,
+    inspector: Inspector,
+    dashboard: Dashboard,
+    viewer_command: String,
+    screenshot: ScreenshotSettings,
+    upload_path: String,
+    upload_dither: bool,
+    flash_path: String,
+    flash_offset: String,
+    flash_progress: Option<(usize, usize)>,
+    flash_status: String,
+    logs: LogView,
+    terminal: Terminal,
+    stats: Option<LinkStats>,
     connected: bool, // As for serial to device, no matter where it is
     decided_backend: bool,
+    backend: Option<Backend>,
     remote_address: String,
+    remote_device: String,
+    // Found with mDNS, see discovery.rs
+    servers: Vec<Server>,
+    rx_servers: Option<Receiver<Vec<Server>>>,
+    discovery: Option<mdns_sd::ServiceDaemon>,
+    profile: Profile,
+    // Name or path typed in the settings
+    profile_name: String,
+    profiles: Vec<String>,
+    // What was picked last, saved in the settings
+    profile_choice: Option<String>,
+    // A loaded profile still has to reach the backend
+    send_profile: bool,
     first_run: bool,
 }
 
 impl MyApp {
-    pub fn new(tx_gui: Sender<SendToGui>, rx_gui: Receiver<SendToGui>, port: Option<String>, baudrate: String) -> Self {
+    pub fn new(tx_gui: Sender<SendToGui>, rx_gui: Receiver<SendToGui>, settings: Settings, viewer_command: String, screenshot_dir: String) -> Self {
+        let mut profile = Profile::default();
+        if let Some(name) = &settings.profile {
+            match Profile::find(name) {
+                Ok(x) => profile = x,
+                Err(x) => error!("{}, using the default profile", x),
+            }
+        }
+        let mut logs = LogView::default();
+        if let Err(x) = logs.set_log_regex(&profile.log_regex) {
+            error!("{}", x);
+        }
+        let mut dashboard = Dashboard::new(settings.baud_rate.clone());
+        dashboard.open = settings.dashboard_open;
+        dashboard.columns = settings.dashboard_columns;
+        dashboard.log_height = settings.dashboard_log_height;
         Self {
             tx_serial: None,
             tx_gui,
             rx_gui,
             sel_port: 0,
-            port: port.clone(),
+            port: settings.port,
             ports: Vec::new(),
-            baud_rate: baudrate,
-            image: Vec::new(),
-            logs: String::new(),
+            baud_rate: settings.baud_rate,
+            texture: None,
+            frame: None,
+            inspector: Inspector::default(),
+            dashboard,
+            viewer_command,
+            screenshot: ScreenshotSettings::new(screenshot_dir),
+            upload_path: String::new(),
+            upload_dither: true,
+            flash_path: String::new(),
+            flash_offset: format!("{:#x}", flash::DEFAULT_OFFSET),
+            flash_progress: None,
+            flash_status: String::new(),
+            logs,
+            terminal: Terminal::default(),
+            stats: None,
             connected: false,
-            decided_backend: port.is_some(),
-            remote_address: String::from(":24377"),
+            decided_backend: false, // Set once a backend actually started
+            backend: settings.backend,
+            remote_address: settings.remote_address,
+            remote_device: settings.remote_device,
+            servers: Vec::new(),
+            rx_servers: None,
+            discovery: None,
+            send_profile: settings.profile.is_some(),
+            profile_choice: settings.profile,
+            profile_name: profile.name.clone(),
+            profiles: Profile::available(),
+            profile,
             first_run: false,
         }
     }
+
+    fn show_frame(&mut self, ctx: &egui::Context, rgba: RgbaImage) {
+        let color_image = egui::ColorImage::from_rgba_unmultiplied(
+            [rgba.width() as usize, rgba.height() as usize],
+            rgba.as_flat_samples().as_slice(),
+        );
+        self.texture = Some(ctx.load_texture("screen", color_image, TextureOptions::NEAREST));
+        self.frame = Some(rgba);
+    }
+
+    pub fn start_discovery(&mut self, ctx: &egui::Context) {
+        let (tx_servers, rx_servers) = channel();
+        let ctx = ctx.clone();
+        let res = discovery::browse(move |servers| {
+            let _ = tx_servers.send(servers);
+            ctx.request_repaint();
+        });
+        match res {
+            Ok(x) => {
+                self.discovery = Some(x);
+                self.rx_servers = Some(rx_servers);
+            }
+            Err(x) => {
+                error!("{}", x);
+            }
+        }
+    }
+
+    pub fn write_logs(&mut self, dir: &std::path::Path) {
+        self.logs.write_to(dir, "watchy-scom");
+        self.dashboard.log_dir = Some(dir.to_path_buf());
+    }
+
+    fn start_local(&mut self) {
+        let tx_gui_clone = self.tx_gui.clone();
+        let (tx_serial, rx_serial) = channel();
+        self.tx_serial = Some(tx_serial);
+        thread::spawn(move || {
+            serial::main(tx_gui_clone, rx_serial);
+        });
+        self.decided_backend = true;
+        self.backend = Some(Backend::Local);
+    }
+
+    fn start_remote(&mut self) {
+        let rem = match self.remote_address.to_remote_addr() {
+            Ok(x) => x,
+            Err(x) => {
+                error!("Invalid remote address {}: {}", self.remote_address, x);
+                return;
+            }
+        };
+        let tx_gui_clone = self.tx_gui.clone();
+        let device = self.remote_device.clone();
+        let (tx_serial, rx_serial) = channel();
+        self.tx_serial = Some(tx_serial);
+        thread::spawn(move || {
+            run_remote(rem, device, tx_gui_clone, rx_serial);
+        });
+        self.decided_backend = true;
+        self.backend = Some(Backend::Remote);
+    }
+
+    fn use_profile(&mut self, profile: Profile) {
+        if let Err(x) = self.logs.set_log_regex(&profile.log_regex) {
+            error!("{}", x);
+        }
+        self.profile_name = profile.name.clone();
+        self.profile = profile;
+    }
+
+    // Picked in the settings, the backends and the dashboard switch too
+    fn load_profile(&mut self, name: &str) {
+        match Profile::find(name) {
+            Ok(x) => {
+                debug!("Loaded profile {}", x.name);
+                self.profile_choice = Some(name.to_string());
+                self.use_profile(x);
+                self.send_profile = true;
+                self.dashboard.broadcast(SetProfile(self.profile.clone()));
+            }
+            Err(x) => {
+                error!("{}", x);
+            }
+        }
+    }
 }
 
 pub fn send_serial(tx_serial: Option<Sender<SendToSerial>>, message: SendToSerial) {
@@ -110,77 +356,202 @@ pub fn send_serial(tx_serial: Option<Sender<SendToSerial>>, message: SendToSeria
     }
 }
 
+pub fn upload_image(tx_serial: Option<Sender<SendToSerial>>, path: &std::path::Path, dither: bool, profile: &Profile) {
+    match image::open(path) {
+        Ok(img) => {
+            debug!("Uploading image {}", path.display());
+            send_serial(tx_serial, SendImage(bitmap::pack(&img, profile.width as usize, profile.height as usize, dither)));
+        }
+        Err(x) => {
+            error!("Failed to open image {}: {}", path.display(), x);
+        }
+    }
+}
+
+pub fn flash_firmware(tx_serial: Option<Sender<SendToSerial>>, path: &std::path::Path, offset: u32) -> bool {
+    match std::fs::read(path) {
+        Ok(image) => {
+            debug!("Flashing {} at {:#x}", path.display(), offset);
+            send_serial(tx_serial, Flash(offset, image));
+            true
+        }
+        Err(x) => {
+            error!("Failed to read firmware {}: {}", path.display(), x);
+            false
+        }
+    }
+}
+
+pub fn open_in_viewer(viewer_command: &str, frame: &RgbaImage) {
+    let path = std::env::temp_dir().join("watchy-scom.png");
+    if let Err(x) = frame.save_with_format(&path, image::ImageFormat::Png) {
+        error!("Failed to save image to {}: {}", path.display(), x);
+        return;
+    }
+    let mut parts = viewer_command.split_whitespace();
+    let Some(program) = parts.next() else {
+        error!("No viewer command set");
+        return;
+    };
+    match Command::new(program).args(parts).arg(&path).spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+        Err(x) => {
+            error!("Failed to execute viewer {}: {}", viewer_command, x);
+        }
+    }
+}
+
+// Maps a position inside the displayed image to watch panel pixels
+pub fn screen_coordinates(pos: Vec2, size: Vec2, profile: &Profile) -> (u16, u16) {
+    let (width, height) = (profile.width as f32, profile.height as f32);
+    let x = (pos.x / size.x * width).clamp(0.0, width - 1.0);
+    let y = (pos.y / size.y * height).clamp(0.0, height - 1.0);
+    (x as u16, y as u16)
+}
+
 impl eframe::App for MyApp {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let settings = Settings {
+            backend: self.backend,
+            remote_address: self.remote_address.clone(),
+            remote_device: self.remote_device.clone(),
+            port: self.port.clone(),
+            baud_rate: self.baud_rate.clone(),
+            profile: self.profile_choice.clone(),
+            dashboard_open: self.dashboard.open,
+            dashboard_columns: self.dashboard.columns,
+            dashboard_log_height: self.dashboard.log_height,
+        };
+        eframe::set_value(storage, settings::KEY, &settings);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
 
-            if self.first_run == false {
+            if !self.first_run {
                 self.first_run = true;
-                if self.decided_backend == true && self.port.is_some() {
-                    // create local thing
-                    let tx_gui_clone = self.tx_gui.clone();
-                    let (tx_serial, rx_serial) = channel();
-                    self.tx_serial = Some(tx_serial);
-                    thread::spawn(move || {
-                        serial::main(tx_gui_clone, rx_serial);
-                    });
-                    self.decided_backend = true;
-
-                    let baud_rate: usize = self.baud_rate.parse().unwrap();
-                    send_serial(self.tx_serial.clone(), SelectPort(self.port.clone().unwrap(), baud_rate));
-                    self.connected = true;
-                    
-                    self.ports.push("".to_string());
-                    self.ports.push(self.port.clone().unwrap());
-                    self.sel_port = 1;
+                // Back to the watch of the last session
+                match self.backend {
+                    Some(Backend::Local) => self.start_local(),
+                    Some(Backend::Remote) => self.start_remote(),
+                    None => {}
+                }
+                if let (Some(port), Ok(baud_rate)) = (self.port.clone(), self.baud_rate.parse::<usize>()) {
+                    if self.tx_serial.is_some() {
+                        info!("Reconnecting to {}", port);
+                        send_serial(self.tx_serial.clone(), SelectPort(port.clone(), baud_rate));
+                        self.connected = true;
+
+                        self.ports.push("".to_string());
+                        self.ports.push(port);
+                        self.sel_port = 1;
+                    }
+                }
+            }
+
+            if self.send_profile && self.tx_serial.is_some() {
+                self.send_profile = false;
+                send_serial(self.tx_serial.clone(), SetProfile(self.profile.clone()));
+            }
+
+            // Key bindings of the profile, unless something like the terminal has the focus
+            if self.connected && ctx.memory(|x| x.focused().is_none()) {
+                for button in &self.profile.buttons {
+                    let key = button.key.as_deref().and_then(egui::Key::from_name);
+                    if key.is_some_and(|key| ctx.input(|i| i.key_pressed(key))) {
+                        debug!("Key for {} pressed", button.label);
+                        send_serial(self.tx_serial.clone(), SendMessage(button.command.clone()));
+                    }
+                }
+            }
+
+            if let Some(rx_servers) = &self.rx_servers {
+                while let Ok(x) = rx_servers.try_recv() {
+                    self.servers = x;
                 }
             }
 
-            match self.rx_gui.recv_timeout(Duration::from_millis(25)) {
-                Ok(x) => match x {
+            // The relay thread in main() asks for a repaint when something arrives
+            while let Ok(x) = self.rx_gui.try_recv() {
+                match x {
                     SendToGui::Ports(x) => {
                         self.ports = x;
                     }
                     SendToGui::LogToShow(input) => {
                         debug!("Received logs to show: {}", input);
-                        // Cleans
-                        let re = Regex::new(r"^src/.*").unwrap();
-                        let mut filtered_lines = String::new();
-                        for line in input.lines() {
-                            if re.is_match(line) {
-                                filtered_lines.push_str(line);
-                                filtered_lines.push('\n'); // Add a newline character to separate lines
-                            } else {
-                                if !line.is_empty() {
-                                    error!("Rejected line: {}", line);
-                                    let regex = Regex::new("[^\x00-\x7F]").unwrap();
-                                
-                                    let matches: Vec<_> = regex.find_iter(line).collect();
-                                
-                                    // Check if the number of weird bytes exceeds 30
-                                    if matches.len() > 30 {
-                                        debug!("We probably catched the scren, requesting an update...");
-                                        send_serial(self.tx_serial.clone(), SendMessage("screen:".to_string()));
-                                    }
-                                }
+                        for line in self.logs.push(&input) {
+                            debug!("Unrecognized line: {}", line);
+                            if looks_like_screen(&line) {
+                                debug!("We probably catched the scren, requesting an update...");
+                                send_serial(self.tx_serial.clone(), SendMessage(self.profile.screen_command.clone()));
                             }
                         }
-
-                        filtered_lines = filtered_lines.replace("\n\n", "\n");
-                        //let result2 = re_non_standard.replace_all(&result, "");
-                        self.logs.push_str(&filtered_lines.clone()); // not sure about the clone
                     }
                     SendToGui::ShowPng(x) => {
                         debug!("Received png");
-                        self.image = x;
+                        match image::load_from_memory(&x) {
+                            Ok(decoded) => {
+                                self.show_frame(ctx, decoded.to_rgba8());
+                            }
+                            Err(x) => {
+                                error!("Failed to decode png: {}", x);
+                            }
+                        }
+                    }
+                    SendToGui::Stats(x) => {
+                        self.stats = Some(x);
+                    }
+                    SendToGui::Devices(x) => {
+                        info!("Server hosts devices: {:?}", x);
+                    }
+                    SendToGui::Connected(x) => {
+                        debug!("Connected to server: {}", x);
+                        // Servers started with --serial have the port open already
+                        if !x || self.port.is_none() {
+                            self.connected = x;
+                        }
+                    }
+                    SendToGui::Profile(x) => {
+                        if let Err(x) = x.validate() {
+                            error!("Ignoring profile from backend: {}", x);
+                        } else if x != self.profile {
+                            info!("Backend uses profile {}", x.name);
+                            self.use_profile(x);
+                        }
+                    }
+                    SendToGui::FlashProgress(written, total) => {
+                        self.flash_progress = Some((written, total));
+                    }
+                    SendToGui::FlashDone(x) => {
+                        self.flash_progress = None;
+                        self.flash_status = match x {
+                            Ok(()) => String::from("Flashing done"),
+                            Err(x) => format!("Flashing failed: {}", x),
+                        };
                     }
-                },
-                Err(_x) => {
-                    /*
-                    if _x.to_string() != "receiving on an empty channel" {
-                        error!("Failed to recv in gui: {}", _x);
+                    SendToGui::ShowFrameRle(_, _, _, _) => {
+                        // Decoded in remote.rs, it keeps the previous frame
+                        error!("Received a compressed frame outside of the remote backend");
+                    }
+                    SendToGui::ShowFrame(width, height, bits) => {
+                        debug!("Received frame {}x{}", width, height);
+                        let img = bitmap::unpack(&bits, width as usize, height as usize);
+                        self.show_frame(ctx, image::DynamicImage::ImageRgb8(img).to_rgba8());
+                    }
+                }
+            }
+
+            if self.connected {
+                let dropped: Vec<egui::DroppedFile> = ctx.input(|i| i.raw.dropped_files.clone());
+                for file in dropped {
+                    if let Some(path) = file.path {
+                        upload_image(self.tx_serial.clone(), &path, self.upload_dither, &self.profile);
+                        self.upload_path = path.display().to_string();
                     }
-                    */
                 }
             }
 
@@ -189,36 +560,77 @@ impl eframe::App for MyApp {
                     .resizable(false)
                     .min_height(130.0)
                     .show_inside(ui, |ui| {
-                        ui.heading("Settings");
+                        ui.horizontal(|ui| {
+                            ui.heading("Settings");
+                            if ui.button("Dashboard").clicked() {
+                                debug!("Opening the dashboard");
+                                self.dashboard.open = true;
+                            }
+                        });
 
                         if !self.decided_backend {
                             ui.horizontal(|ui| {
                                 ui.label("Remote address:");
                                 ui.add(egui::TextEdit::singleline(&mut self.remote_address));
+                                ui.label("Device:");
+                                ui.add(egui::TextEdit::singleline(&mut self.remote_device));
 
 
                             });
-                            if ui.add( egui::Button::new("Use remote")).clicked() {
-                                let tx_gui_clone = self.tx_gui.clone();
-                                let rem = self.remote_address.to_remote_addr().expect("Failed to convert to address");
-                                let (tx_serial, rx_serial) = channel();
-                                self.tx_serial = Some(tx_serial);
-                                thread::spawn(move || {
-                                    run_remote(rem, tx_gui_clone, rx_serial);
-                                });
-                                self.decided_backend = true;
-                            }
+                            ui.horizontal_wrapped(|ui| {
+                                if ui.add( egui::Button::new("Use remote")).clicked() {
+                                    self.start_remote();
+                                }
+                                if !self.servers.is_empty() {
+                                    ui.label("or found on the network:");
+                                }
+                                let mut picked = None;
+                                for server in &self.servers {
+                                    for device in &server.devices {
+                                        if ui.button(format!("{}@{}", device, server.name)).on_hover_text(&server.address).clicked() {
+                                            picked = Some((server.address.clone(), device.clone()));
+                                        }
+                                    }
+                                    // Devices appear once a client names one
+                                    if server.devices.is_empty() && ui.button(&server.name).on_hover_text(&server.address).clicked() {
+                                        picked = Some((server.address.clone(), self.remote_device.clone()));
+                                    }
+                                }
+                                if let Some((address, device)) = picked {
+                                    self.remote_address = address;
+                                    self.remote_device = device;
+                                    self.start_remote();
+                                }
+                            });
                             if ui.add( egui::Button::new("Use local")).clicked() {
-                                let tx_gui_clone = self.tx_gui.clone();
-                                let (tx_serial, rx_serial) = channel();
-                                self.tx_serial = Some(tx_serial);
-                                thread::spawn(move || {
-                                    serial::main(tx_gui_clone, rx_serial);
-                                });
-                                self.decided_backend = true;
+                                self.start_local();
                             }
                         }
 
+                        ui.horizontal(|ui| {
+                            ui.label("Profile:");
+                            let mut picked = None;
+                            egui::ComboBox::from_id_source("profile")
+                                .selected_text(&self.profile.name)
+                                .show_ui(ui, |ui| {
+                                    for name in &self.profiles {
+                                        if ui.selectable_label(*name == self.profile.name, name).clicked() {
+                                            picked = Some(name.clone());
+                                        }
+                                    }
+                                });
+                            ui.add(egui::TextEdit::singleline(&mut self.profile_name).hint_text("name or path").desired_width(200.0));
+                            if ui.add(egui::Button::new("Load")).clicked() {
+                                picked = Some(self.profile_name.clone());
+                            }
+                            if ui.add(egui::Button::new("Rescan")).clicked() {
+                                self.profiles = Profile::available();
+                            }
+                            if let Some(name) = picked {
+                                self.load_profile(&name);
+                            }
+                        });
+
                         ui.horizontal(|ui| {
                             ui.label("Baud rate:");
                             ui.add_enabled(self.decided_backend, egui::TextEdit::singleline(&mut self.baud_rate));
@@ -237,110 +649,178 @@ impl eframe::App for MyApp {
                             );
                         }
 
-                        if self.sel_port != 0 {
-                            if ui
+                        if self.sel_port != 0
+                            && ui
                                 .add(egui::Button::new(format!(
                                     "Connect to {} with baud rate {}",
                                     self.ports[self.sel_port].clone(),
                                     self.baud_rate
                                 )))
                                 .clicked()
-                            {
-                                let baud_rate: usize = self.baud_rate.parse().unwrap();
-                                send_serial(self.tx_serial.clone(), SelectPort(self.ports[self.sel_port].clone(), baud_rate));
-                                self.connected = true;
-                            }
+                        {
+                            let baud_rate: usize = self.baud_rate.parse().unwrap();
+                            send_serial(self.tx_serial.clone(), SelectPort(self.ports[self.sel_port].clone(), baud_rate));
+                            self.port = Some(self.ports[self.sel_port].clone());
+                            self.connected = true;
                         }
                     });
                 if self.connected {
-                    ui.horizontal(|ui| {
-                        if ui.add(egui::Button::new("Back")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("back-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Menu")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("menu-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Up")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("up-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Down")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("down-button:".to_string()));
-                        }
-                    });
-                    ui.horizontal(|ui| {
-                        if ui.add(egui::Button::new("Long back")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("long-back-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Long menu")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("long-menu-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Long up")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("long-up-button:".to_string()));
-                        }
-                        if ui.add(egui::Button::new("Long down")).clicked() {
-                            debug!("Button to button clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("long-down-button:".to_string()));
-                        }
-                    });
+                    for row in self.profile.buttons.chunks(4) {
+                        ui.horizontal(|ui| {
+                            for button in row {
+                                if ui.add(egui::Button::new(&button.label)).clicked() {
+                                    debug!("Button to button clicked");
+                                    send_serial(self.tx_serial.clone(), SendMessage(button.command.clone()));
+                                }
+                            }
+                        });
+                    }
                     ui.horizontal(|ui| {
                         if ui.add(egui::Button::new("Update screen")).clicked() {
                             debug!("Button to update screen clicked");
-                            send_serial(self.tx_serial.clone(), SendMessage("screen:".to_string()));
+                            send_serial(self.tx_serial.clone(), SendMessage(self.profile.screen_command.clone()));
                         }
-                        if !self.image.is_empty() {
+                        if let Some(frame) = &self.frame {
+                            if ui.add(egui::Button::new("Inspect screen")).clicked() {
+                                debug!("Button to inspect image clicked");
+                                self.inspector.open = true;
+                            }
                             if ui.add(egui::Button::new("Open screen")).clicked() {
                                 debug!("Button to save image clicked");
-                                let _ = std::fs::write("/tmp/watchy-scom.png", &self.image.clone());
-                                Command::new("code")
-                                    .arg("/tmp/watchy-scom.png")
-                                    .spawn()
-                                    .expect("failed to execute process");
+                                open_in_viewer(&self.viewer_command, frame);
                             }
+                            ui.label("with");
+                            ui.add(egui::TextEdit::singleline(&mut self.viewer_command).desired_width(80.0));
                             if ui.add(egui::Button::new("Reset")).clicked() {
                                 debug!("Button to reset the watchy clicked");
-                                send_serial(self.tx_serial.clone(), SendMessage("reset:".to_string()));
+                                send_serial(self.tx_serial.clone(), SendMessage(self.profile.reset_command.clone()));
+                            }
+                        }
+                    });
+                    if let Some(frame) = &self.frame {
+                        ui.horizontal(|ui| {
+                            if ui.add(egui::Button::new("Screenshot")).clicked() {
+                                debug!("Button to save screenshot clicked");
+                                screenshot::save(&self.screenshot, frame);
+                            }
+                            egui::ComboBox::from_id_source("screenshot_scale")
+                                .selected_text(format!("{}x", self.screenshot.scale))
+                                .show_ui(ui, |ui| {
+                                    for scale in [1, 2, 4] {
+                                        ui.selectable_value(&mut self.screenshot.scale, scale, format!("{}x", scale));
+                                    }
+                                });
+                            egui::ComboBox::from_id_source("screenshot_format")
+                                .selected_text(self.screenshot.format.extension())
+                                .show_ui(ui, |ui| {
+                                    for format in ScreenshotFormat::ALL {
+                                        ui.selectable_value(&mut self.screenshot.format, format, format.extension());
+                                    }
+                                });
+                            ui.label("to");
+                            ui.add(egui::TextEdit::singleline(&mut self.screenshot.directory).desired_width(160.0));
+                        });
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.add(egui::Button::new("Upload image")).clicked() {
+                            debug!("Button to upload image clicked");
+                            upload_image(self.tx_serial.clone(), std::path::Path::new(&self.upload_path), self.upload_dither, &self.profile);
+                        }
+                        ui.add(egui::TextEdit::singleline(&mut self.upload_path).hint_text("or drop a file here").desired_width(200.0));
+                        ui.checkbox(&mut self.upload_dither, "Dither");
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(self.flash_progress.is_none(), egui::Button::new("Flash firmware")).clicked() {
+                            debug!("Button to flash firmware clicked");
+                            match flash::parse_offset(&self.flash_offset) {
+                                Some(offset) => {
+                                    if flash_firmware(self.tx_serial.clone(), std::path::Path::new(&self.flash_path), offset) {
+                                        self.flash_progress = Some((0, 0));
+                                        self.flash_status = String::from("Entering the bootloader");
+                                    }
+                                }
+                                None => {
+                                    error!("Invalid flash offset {}", self.flash_offset);
+                                }
                             }
                         }
+                        ui.add(egui::TextEdit::singleline(&mut self.flash_path).hint_text("firmware.bin").desired_width(200.0));
+                        ui.label("at");
+                        ui.add(egui::TextEdit::singleline(&mut self.flash_offset).desired_width(70.0));
                     });
+                    if let Some((written, total)) = self.flash_progress {
+                        ui.add(egui::ProgressBar::new(written as f32 / total.max(1) as f32).text(format!("{} / {} bytes", written, total)));
+                    }
+                    if !self.flash_status.is_empty() {
+                        ui.label(&self.flash_status);
+                    }
+                    if let Some(stats) = &self.stats {
+                        egui::CollapsingHeader::new("Link statistics").show(ui, |ui| {
+                            egui::Grid::new("stats_grid").num_columns(2).show(ui, |ui| {
+                                ui.label("Throughput");
+                                ui.label(format!("{:.0} B/s", stats.bytes_per_sec));
+                                ui.end_row();
+                                ui.label("Frames");
+                                ui.label(format!("{:.1} /s", stats.frames_per_sec));
+                                ui.end_row();
+                                ui.label("Decode latency");
+                                ui.label(format!("{:.1} ms", stats.decode_latency_ms));
+                                ui.end_row();
+                                ui.label("Wrong length frames");
+                                ui.label(format!("{}", stats.wrong_length_frames));
+                                ui.end_row();
+                                ui.label("Resyncs");
+                                ui.label(format!("{}", stats.resyncs));
+                                ui.end_row();
+                            });
+                        });
+                    }
                 }
-                if !self.image.is_empty() {
+                if let Some(texture) = &self.texture {
                     ui.horizontal_centered(|ui| {
                         //debug!("Showing image");
-                        //let _ = std::fs::write("output.png", &self.image.clone());
-                        RetainedImage::from_image_bytes("png", &self.image)
-                            .unwrap()
-                            .show_size(ui, Vec2::new(400.0, 400.0));
-                        //let eimg = egui::Image::from_bytes("", self.image.clone()).fit_to_exact_size(Vec2::new(200.0, 200.0)).show_loading_spinner(true);
-                        //let uh = ui.add(eimg);
+                        let response = ui.add(
+                            egui::Image::new(texture)
+                                .fit_to_exact_size(Vec2::new(400.0, 400.0))
+                                .sense(egui::Sense::click()),
+                        );
+                        if response.clicked() {
+                            if let Some(pos) = response.interact_pointer_pos() {
+                                let (x, y) = screen_coordinates(pos - response.rect.min, response.rect.size(), &self.profile);
+                                debug!("Screen clicked at {}x{}", x, y);
+                                send_serial(self.tx_serial.clone(), Touch(x, y));
+                            }
+                        }
                     });
                 }
             });
 
+            if self.inspector.open {
+                if let (Some(texture), Some(frame)) = (&self.texture, &self.frame) {
+                    self.inspector.show(ctx, texture, frame);
+                }
+            }
+
+            self.dashboard.poll(ctx);
+            if self.dashboard.open {
+                self.dashboard.show(ctx, &self.profile);
+            }
+
             egui::SidePanel::right("right_panel")
                 .resizable(true)
                 .default_width(600.0)
                 .show_inside(ui, |ui| {
-                    //debug!("self.logs: {}", self.logs);
-                    egui::ScrollArea::vertical()
-                        .stick_to_bottom(true)
-                        .show(ui, |ui| {
-                            ui.add_sized(
-                                ui.available_size(),
-                                egui::TextEdit::multiline(&mut self.logs)
-                                    .text_color(Color32::WHITE)
-                                    .interactive(true),
-                            );
+                    if self.connected {
+                        egui::TopBottomPanel::bottom("terminal_panel").show_inside(ui, |ui| {
+                            if let Some(message) = self.terminal.show(ui) {
+                                debug!("Sending from terminal: {:?}", message);
+                                send_serial(self.tx_serial.clone(), message);
+                            }
                         });
+                    }
+                    self.logs.show(ui);
                 });
 
-            ctx.request_repaint_after(Duration::from_millis(40));
         });
     }
 }
@@ -13,15 +13,37 @@ use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::thread;
 
-use crate::api::{SendToGui, SendToSerial};
+use crate::api::{SendToGui, SendToSerial, Tagged};
+use crate::bitmap;
+use crate::codec;
 
-pub fn send_data(server_id: Endpoint, handler: Arc<NodeHandler<SendToSerial>>, message: SendToSerial) {
+pub fn send_data(server_id: Endpoint, handler: Arc<NodeHandler<SendToSerial>>, device: &str, message: SendToSerial) {
+    let message = Tagged {
+        device: device.to_string(),
+        message,
+    };
     let output_data = bincode::serialize(&message).unwrap();
     handler.network().send(server_id, &output_data);
 }
 
+// Rebuilds the full frame from a compressed keyframe or delta, both must cover
+// the whole screen
+pub fn decode_frame(last_frame: &[u8], width: u16, height: u16, keyframe: bool, data: &[u8]) -> Option<Vec<u8>> {
+    let decoded = codec::rle_decode(data)?;
+    if decoded.len() != bitmap::packed_len(width as usize, height as usize) {
+        None
+    } else if keyframe {
+        Some(decoded)
+    } else if decoded.len() == last_frame.len() {
+        Some(codec::xor(last_frame, &decoded))
+    } else {
+        None
+    }
+}
+
 pub fn run_remote(
     remote_addr: RemoteAddr,
+    device: String,
     tx_gui: Sender<crate::api::SendToGui>,
     rx_serial: Receiver<crate::api::SendToSerial>,
 ) {
@@ -33,22 +55,34 @@ pub fn run_remote(
         .connect(Transport::Ws, remote_addr.clone())
         .unwrap();
 
-    thread::spawn(move || loop {
-        if let Ok(event) = rx_serial.recv() {
-            send_data(server_id, handler.clone(), event);
-        }
-    });
+    // Messages wait in the channel until the connection is established
+    let mut rx_serial = Some(rx_serial);
 
+    let mut last_frame: Vec<u8> = Vec::new();
     listener.for_each(move |event| match event {
         NodeEvent::Network(net_event) => match net_event {
             NetEvent::Connected(_, established) => {
                 if established {
+                    if let Some(rx_serial) = rx_serial.take() {
+                        // Attaches to the device, the server replays its logs and screen
+                        send_data(server_id, handler.clone(), &device, SendToSerial::AskForDevices());
+                        let handler = handler.clone();
+                        let device = device.clone();
+                        thread::spawn(move || {
+                            for event in rx_serial {
+                                send_data(server_id, handler.clone(), &device, event);
+                            }
+                            // Nothing more to send, like a headless command that is done
+                            handler.stop();
+                        });
+                    }
                     info!(
                         "Connected to server at {} by {}",
                         server_id.addr(),
                         Transport::Ws
                     );
                     info!("Client identified by local port: {}", local_addr.port());
+                    info!("Watching device {}", device);
                 } else {
                     error!(
                         "Cannot connect to server at {} by {}",
@@ -56,21 +90,67 @@ pub fn run_remote(
                         Transport::Ws
                     );
                 }
+                let _ = tx_gui.send(SendToGui::Connected(established));
             }
             NetEvent::Accepted(_, _) => unreachable!(), // Only generated when a listener accepts
             NetEvent::Message(_, input_data) => {
                 debug!("Received raw input data with length: {}", input_data.len());
-                let message: SendToGui = bincode::deserialize(input_data).unwrap();
+                let tagged: Tagged<SendToGui> = bincode::deserialize(input_data).unwrap();
+                if tagged.device != device {
+                    debug!("Skipping message for device {}", tagged.device);
+                    return;
+                }
+                let mut message = tagged.message;
+                if let SendToGui::ShowFrameRle(width, height, keyframe, data) = message {
+                    match decode_frame(&last_frame, width, height, keyframe, &data) {
+                        Some(frame) => {
+                            last_frame = frame.clone();
+                            message = SendToGui::ShowFrame(width, height, frame);
+                        }
+                        None => {
+                            error!("Failed to decode frame, waiting for a keyframe");
+                            // Later deltas would build on a frame the server doesn't have
+                            last_frame.clear();
+                            return;
+                        }
+                    }
+                }
                 if tx_gui.send(message).is_err() {
-                    error!("Failed to send message to gui");
+                    // Removed from the dashboard, nobody listens anymore
+                    debug!("Gui is gone, disconnecting");
+                    handler.stop();
                 }
             }
             NetEvent::Disconnected(_) => {
                 error!("Server is disconnected");
+                let _ = tx_gui.send(SendToGui::Connected(false));
             }
         },
-        NodeEvent::Signal(signal) => match signal {
-            _ => {}
-        },
+        NodeEvent::Signal(_) => {}
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_keyframes_and_deltas() {
+        let keyframe = vec![0xFF; 4];
+        let data = codec::rle_encode(&keyframe);
+        assert_eq!(decode_frame(&[], 8, 4, true, &data), Some(keyframe.clone()));
+        let delta = codec::rle_encode(&[0, 0x0F, 0, 0]);
+        assert_eq!(decode_frame(&keyframe, 8, 4, false, &delta), Some(vec![0xFF, 0xF0, 0xFF, 0xFF]));
+    }
+
+    #[test]
+    fn refuses_frames_of_the_wrong_size() {
+        // A short keyframe
+        assert_eq!(decode_frame(&[], 8, 4, true, &codec::rle_encode(&[0xFF; 3])), None);
+        // A delta without a keyframe first
+        assert_eq!(decode_frame(&[], 8, 4, false, &codec::rle_encode(&[0; 4])), None);
+        // A delta for another screen size
+        assert_eq!(decode_frame(&[0; 4], 16, 4, false, &codec::rle_encode(&[0; 8])), None);
+        assert_eq!(decode_frame(&[], 8, 4, true, &[0, 0xFF]), None);
+    }
+}
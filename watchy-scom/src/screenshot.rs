@@ -0,0 +1,133 @@
+// Logging
+use log::{debug, error};
+
+// Other
+use image::{imageops, ImageFormat, RgbaImage};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenshotFormat {
+    Png,
+    Bmp,
+    Pbm,
+}
+
+impl ScreenshotFormat {
+    pub const ALL: [ScreenshotFormat; 3] = [
+        ScreenshotFormat::Png,
+        ScreenshotFormat::Bmp,
+        ScreenshotFormat::Pbm,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScreenshotFormat::Png => "png",
+            ScreenshotFormat::Bmp => "bmp",
+            ScreenshotFormat::Pbm => "pbm",
+        }
+    }
+}
+
+pub struct ScreenshotSettings {
+    pub directory: String,
+    pub scale: u32,
+    pub format: ScreenshotFormat,
+}
+
+impl ScreenshotSettings {
+    pub fn new(directory: String) -> Self {
+        Self {
+            directory,
+            scale: 1,
+            format: ScreenshotFormat::Png,
+        }
+    }
+}
+
+pub fn save(settings: &ScreenshotSettings, frame: &RgbaImage) -> Option<PathBuf> {
+    let directory = Path::new(&settings.directory);
+    if let Err(x) = std::fs::create_dir_all(directory) {
+        error!(
+            "Failed to create screenshot directory {}: {}",
+            directory.display(),
+            x
+        );
+        return None;
+    }
+    let name = format!(
+        "watchy-scom-{}.{}",
+        chrono::Local::now().format("%Y-%m-%d-%H-%M-%S-%3f"),
+        settings.format.extension()
+    );
+    let path = directory.join(name);
+
+    let scaled = imageops::resize(
+        frame,
+        frame.width() * settings.scale,
+        frame.height() * settings.scale,
+        imageops::FilterType::Nearest,
+    );
+    let res = match settings.format {
+        ScreenshotFormat::Png => image::DynamicImage::ImageRgba8(scaled)
+            .to_rgb8()
+            .save_with_format(&path, ImageFormat::Png)
+            .map_err(|x| x.to_string()),
+        ScreenshotFormat::Bmp => image::DynamicImage::ImageRgba8(scaled)
+            .to_rgb8()
+            .save_with_format(&path, ImageFormat::Bmp)
+            .map_err(|x| x.to_string()),
+        ScreenshotFormat::Pbm => std::fs::File::create(&path)
+            .and_then(|mut file| file.write_all(&encode_pbm(&scaled)))
+            .map_err(|x| x.to_string()),
+    };
+    match res {
+        Ok(()) => {
+            debug!("Saved screenshot to {}", path.display());
+            Some(path)
+        }
+        Err(x) => {
+            error!("Failed to save screenshot to {}: {}", path.display(), x);
+            None
+        }
+    }
+}
+
+// Raw (P4) pbm, rows are padded to full bytes and 1 means black
+pub fn encode_pbm(frame: &RgbaImage) -> Vec<u8> {
+    let mut out = format!("P4\n{} {}\n", frame.width(), frame.height()).into_bytes();
+    let row_bytes = frame.width().div_ceil(8) as usize;
+    for y in 0..frame.height() {
+        let mut row = vec![0u8; row_bytes];
+        for x in 0..frame.width() {
+            if frame.get_pixel(x, y)[0] <= 127 {
+                row[x as usize / 8] |= 1 << (7 - (x % 8));
+            }
+        }
+        out.extend(row);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn encodes_pbm_rows_padded_and_black_set() {
+        // 10 pixels wide, so every row takes 2 bytes
+        let mut frame = RgbaImage::from_pixel(10, 2, Rgba([255, 255, 255, 255]));
+        frame.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        frame.put_pixel(9, 0, Rgba([0, 0, 0, 255]));
+        frame.put_pixel(8, 1, Rgba([127, 127, 127, 255]));
+        frame.put_pixel(7, 1, Rgba([128, 128, 128, 255]));
+        let pbm = encode_pbm(&frame);
+        let header = b"P4\n10 2\n";
+        assert_eq!(&pbm[..header.len()], header);
+        assert_eq!(
+            &pbm[header.len()..],
+            [0b1000_0000, 0b0100_0000, 0, 0b1000_0000]
+        );
+    }
+}
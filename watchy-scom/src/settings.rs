@@ -0,0 +1,99 @@
+// What the gui remembers between launches, kept by eframe in its storage
+// (app.ron in the data directory, like ~/.local/share/watchy-scom). Window size,
+// position and panel sizes are saved by eframe itself.
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::DEFAULT_DEVICE;
+use crate::dashboard;
+
+pub const KEY: &str = "settings";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Local,
+    Remote,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // None until a backend was chosen once
+    pub backend: Option<Backend>,
+    pub remote_address: String,
+    pub remote_device: String,
+    // Last port connected to, opened again on start
+    pub port: Option<String>,
+    pub baud_rate: String,
+    pub profile: Option<String>,
+    pub dashboard_open: bool,
+    pub dashboard_columns: usize,
+    pub dashboard_log_height: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            remote_address: String::from(":24377"),
+            remote_device: String::from(DEFAULT_DEVICE),
+            port: None,
+            baud_rate: String::from("921600"),
+            profile: None,
+            dashboard_open: false,
+            dashboard_columns: 2,
+            dashboard_log_height: 200.0,
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(storage: Option<&dyn eframe::Storage>) -> Self {
+        storage
+            .and_then(|x| eframe::get_value::<Self>(x, KEY))
+            .unwrap_or_default()
+            .clamped()
+    }
+
+    // app.ron can be edited by hand, a layout the sliders can't make could crash
+    fn clamped(mut self) -> Self {
+        self.dashboard_columns = self
+            .dashboard_columns
+            .clamp(*dashboard::COLUMNS.start(), *dashboard::COLUMNS.end());
+        self.dashboard_log_height = if self.dashboard_log_height.is_finite() {
+            self.dashboard_log_height
+                .clamp(*dashboard::LOG_HEIGHT.start(), *dashboard::LOG_HEIGHT.end())
+        } else {
+            Self::default().dashboard_log_height
+        };
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_dashboard_layout() {
+        let settings = Settings {
+            dashboard_columns: 0,
+            dashboard_log_height: 10_000.0,
+            ..Default::default()
+        }
+        .clamped();
+        assert_eq!(settings.dashboard_columns, 1);
+        assert_eq!(settings.dashboard_log_height, 600.0);
+        let settings = Settings {
+            dashboard_columns: 100,
+            dashboard_log_height: f32::NAN,
+            ..Default::default()
+        }
+        .clamped();
+        assert_eq!(settings.dashboard_columns, 4);
+        assert_eq!(settings.dashboard_log_height, 200.0);
+        let settings = Settings::default().clamped();
+        assert_eq!(settings.dashboard_columns, 2);
+        assert_eq!(settings.dashboard_log_height, 200.0);
+    }
+}
@@ -0,0 +1,274 @@
+// Gui
+use eframe::egui;
+use egui::{Color32, Key};
+
+// Other
+use crate::api::SendToSerial;
+
+const HISTORY_LEN: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    None,
+    Lf,
+    Cr,
+    CrLf,
+}
+
+impl LineEnding {
+    pub const ALL: [LineEnding; 4] = [
+        LineEnding::None,
+        LineEnding::Lf,
+        LineEnding::Cr,
+        LineEnding::CrLf,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::None => "No line ending",
+            LineEnding::Lf => "LF",
+            LineEnding::Cr => "CR",
+            LineEnding::CrLf => "CR LF",
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            LineEnding::None => "",
+            LineEnding::Lf => "\n",
+            LineEnding::Cr => "\r",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputMode {
+    Text,
+    // \n, \r, \t, \0, \\ and \xHH
+    Escaped,
+    // Pairs of hex digits, spaces are ignored
+    Hex,
+}
+
+impl InputMode {
+    pub const ALL: [InputMode; 3] = [InputMode::Text, InputMode::Escaped, InputMode::Hex];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputMode::Text => "Text",
+            InputMode::Escaped => "Escaped",
+            InputMode::Hex => "Hex",
+        }
+    }
+}
+
+// from_str_radix alone would take "+f" as a byte
+fn hex_byte(hex: &str) -> Option<u8> {
+    if hex.len() != 2 || !hex.chars().all(|x| x.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+pub fn parse_escaped(input: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('t') => out.push(b'\t'),
+            Some('0') => out.push(0),
+            Some('\\') => out.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                out.push(hex_byte(&hex).ok_or(format!("Invalid escape \\x{}", hex))?);
+            }
+            Some(x) => return Err(format!("Unknown escape \\{}", x)),
+            None => return Err("Trailing \\".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+pub fn parse_hex(input: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = input.chars().filter(|x| !x.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            hex_byte(&pair).ok_or(format!("Invalid hex byte {}", pair))
+        })
+        .collect()
+}
+
+pub struct Terminal {
+    input: String,
+    history: Vec<String>,
+    // Position while browsing the history with the arrows
+    history_pos: Option<usize>,
+    pub mode: InputMode,
+    error: String,
+    // InkWatchy commands end with ':' and need nothing more
+    pub line_ending: LineEnding,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self {
+            input: String::new(),
+            history: Vec::new(),
+            history_pos: None,
+            mode: InputMode::Text,
+            error: String::new(),
+            line_ending: LineEnding::None,
+        }
+    }
+}
+
+impl Terminal {
+    fn submit(&mut self) -> Option<SendToSerial> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let suffix = self.line_ending.suffix();
+        let parsed = match self.mode {
+            InputMode::Text => Ok(None),
+            InputMode::Escaped => parse_escaped(&self.input).map(Some),
+            InputMode::Hex => parse_hex(&self.input).map(Some),
+        };
+        let message = match parsed {
+            Ok(None) => SendToSerial::SendMessage(self.input.clone() + suffix),
+            Ok(Some(mut bytes)) => {
+                bytes.extend(suffix.as_bytes());
+                SendToSerial::SendBytes(bytes)
+            }
+            Err(x) => {
+                // Kept in the box so it can be fixed
+                self.error = x;
+                return None;
+            }
+        };
+        self.error.clear();
+
+        let input = std::mem::take(&mut self.input);
+        if self.history.last() != Some(&input) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.remove(0);
+            }
+            self.history.push(input);
+        }
+        self.history_pos = None;
+        Some(message)
+    }
+
+    fn browse(&mut self, older: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match (self.history_pos, older) {
+            (None, true) => Some(self.history.len() - 1),
+            (None, false) => None,
+            (Some(0), true) => Some(0),
+            (Some(x), true) => Some(x - 1),
+            (Some(x), false) if x + 1 < self.history.len() => Some(x + 1),
+            (Some(_), false) => None,
+        };
+        self.history_pos = pos;
+        self.input = match pos {
+            Some(x) => self.history[x].clone(),
+            None => String::new(),
+        };
+    }
+
+    // Returns the message to send, with the line ending already appended
+    pub fn show(&mut self, ui: &mut egui::Ui) -> Option<SendToSerial> {
+        let mut send = None;
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut self.input)
+                    .hint_text("Command, Enter sends it")
+                    .code_editor()
+                    .desired_width(ui.available_width() - 280.0),
+            );
+            if response.has_focus() {
+                if ui.input(|i| i.key_pressed(Key::ArrowUp)) {
+                    self.browse(true);
+                }
+                if ui.input(|i| i.key_pressed(Key::ArrowDown)) {
+                    self.browse(false);
+                }
+            }
+            if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                send = self.submit();
+                response.request_focus();
+            }
+            if ui.button("Send").clicked() {
+                send = self.submit();
+            }
+            egui::ComboBox::from_id_source("terminal_mode")
+                .selected_text(self.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in InputMode::ALL {
+                        ui.selectable_value(&mut self.mode, mode, mode.name());
+                    }
+                });
+            egui::ComboBox::from_id_source("terminal_line_ending")
+                .selected_text(self.line_ending.name())
+                .show_ui(ui, |ui| {
+                    for ending in LineEnding::ALL {
+                        ui.selectable_value(&mut self.line_ending, ending, ending.name());
+                    }
+                });
+        });
+        if !self.error.is_empty() {
+            ui.colored_label(Color32::LIGHT_RED, &self.error);
+        }
+        send
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_escapes() {
+        assert_eq!(parse_escaped("a\\nb\\r\\t\\0"), Ok(b"a\nb\r\t\0".to_vec()));
+        assert_eq!(parse_escaped("\\\\x"), Ok(b"\\x".to_vec()));
+        assert_eq!(parse_escaped("\\x1b[0m"), Ok(b"\x1b[0m".to_vec()));
+        assert_eq!(parse_escaped("é"), Ok("é".as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn refuses_bad_escapes() {
+        assert!(parse_escaped("abc\\").is_err());
+        assert!(parse_escaped("\\q").is_err());
+        assert!(parse_escaped("\\x4").is_err());
+        assert!(parse_escaped("\\x+f").is_err());
+        assert!(parse_escaped("\\xg0").is_err());
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(parse_hex("de AD\tbe ef"), Ok(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_hex(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn refuses_bad_hex() {
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("+f").is_err());
+        assert!(parse_hex("0g").is_err());
+        assert!(parse_hex("-1").is_err());
+    }
+}
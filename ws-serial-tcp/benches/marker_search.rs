@@ -0,0 +1,106 @@
+// Compares the old rescanning marker search with parser.rs on a serial stream.
+// Set WATCHY_SCOM_RECORDING to a raw dump of the port to bench a real recording,
+// otherwise a stream of logs and screens is generated.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[path = "../../parser.rs"]
+#[allow(dead_code)]
+mod parser;
+use parser::{FrameParser, ParserEvent};
+
+const START_PACKET: &[u8] = b"thisisastartpack";
+const END_PACKET: &[u8] = b"thisisaendddpack";
+
+fn recorded_stream() -> Vec<u8> {
+    if let Ok(path) = std::env::var("WATCHY_SCOM_RECORDING") {
+        return std::fs::read(path).expect("Failed to read recording");
+    }
+    let mut stream = Vec::new();
+    for frame in 0..50u32 {
+        for line in 0..40u32 {
+            stream.extend(
+                format!(
+                    "src/hardware/rtc.cpp:{}: frame {} tick {}\n",
+                    line, frame, line
+                )
+                .as_bytes(),
+            );
+        }
+        stream.extend(START_PACKET);
+        stream.extend((0..5000u32).map(|x| (x.wrapping_mul(frame + 7) % 251) as u8));
+        stream.extend(END_PACKET);
+    }
+    stream
+}
+
+// The search serial.rs used before parser.rs
+fn find_subsequence(vector: &[u8], subsequence: &[u8]) -> Option<usize> {
+    if subsequence.len() as isize > vector.len() as isize - subsequence.len() as isize {
+        return None;
+    }
+    (0..=(vector.len() - subsequence.len())).find(|&i| vector[i..].starts_with(subsequence))
+}
+
+fn rescanning(stream: &[u8], read_size: usize) -> usize {
+    let mut screens = 0;
+    let mut serial_buf: Vec<u8> = Vec::with_capacity(16000);
+    for chunk in stream.chunks(read_size) {
+        let mut serial_buf_tmp: Vec<u8> = vec![0; 7000];
+        serial_buf_tmp[..chunk.len()].copy_from_slice(chunk);
+        serial_buf.extend(&serial_buf_tmp[0..chunk.len()].to_owned());
+        if let Some(end_pos) = find_subsequence(&serial_buf, END_PACKET) {
+            if let Some(start_pos) = find_subsequence(&serial_buf, START_PACKET) {
+                if start_pos < end_pos {
+                    screens += 1;
+                }
+                let rest = serial_buf[end_pos + END_PACKET.len()..].to_owned();
+                serial_buf.clear();
+                serial_buf.extend(rest);
+            } else {
+                serial_buf.clear();
+            }
+        }
+    }
+    screens
+}
+
+fn streaming(stream: &[u8], read_size: usize) -> usize {
+    let mut screens = 0;
+    let mut parser = FrameParser::new(START_PACKET, END_PACKET).unwrap();
+    for chunk in stream.chunks(read_size) {
+        parser.push(chunk, |event| match event {
+            ParserEvent::Synced => {}
+            ParserEvent::Logs(logs) | ParserEvent::Resynced(logs) => {
+                black_box(logs);
+            }
+            ParserEvent::Screen(screen) => {
+                black_box(screen);
+                screens += 1;
+            }
+        });
+    }
+    screens
+}
+
+fn marker_search(c: &mut Criterion) {
+    let stream = recorded_stream();
+    let mut group = c.benchmark_group("marker_search");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    for read_size in [64, 1024, 7000] {
+        group.bench_with_input(
+            BenchmarkId::new("rescanning", read_size),
+            &read_size,
+            |b, &size| b.iter(|| rescanning(black_box(&stream), size)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("streaming", read_size),
+            &read_size,
+            |b, &size| b.iter(|| streaming(black_box(&stream), size)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, marker_search);
+criterion_main!(benches);
@@ -1,7 +1,14 @@
 mod api;
+mod bitmap;
+mod codec;
+mod discovery;
+mod flash;
+mod parser;
+mod profile;
+mod rfc2217;
 mod serial;
 mod server;
-use api::SendToGui;
+use api::{SendToGui, SendToSerial, Tagged, DEFAULT_DEVICE};
 
 // Logging
 use log::{debug, error, info};
@@ -12,30 +19,237 @@ use message_io::node::{self, NodeHandler};
 use std::net::ToSocketAddrs;
 
 // Threads
-use std::sync::mpsc::{self, RecvTimeoutError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 
 // Arguments
 use clap::Parser;
 
 // Other
 use crate::api::ThreadCom;
+use crate::profile::Profile;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Args {
     #[arg(short, long, help = "Network port to use", default_value_t = 24377)]
     port: u16,
+    #[arg(
+        short,
+        long,
+        help = "Send a full frame every n frames, the rest are compressed deltas",
+        default_value_t = 30
+    )]
+    keyframe_interval: usize,
+    #[arg(
+        short,
+        long,
+        help = "Serial device to open on start as name=path, repeat it for more watches. Clients can't select another port for it"
+    )]
+    serial: Vec<String>,
+    #[arg(short, long, help = "Baud rate for --serial", default_value_t = 921600)]
+    baudrate: usize,
+    #[arg(
+        short = 'c',
+        long,
+        help = "Data bits, parity and stop bits for --serial",
+        default_value = "8N1"
+    )]
+    serial_config: String,
+    #[arg(
+        short,
+        long,
+        help = "RFC 2217 port with raw access to the first --serial device, the next devices use the following ports"
+    )]
+    raw_port: Option<u16>,
+    #[arg(
+        long,
+        help = "Firmware profile of the watches, a name from profiles/ or a path",
+        default_value = profile::DEFAULT_PROFILE
+    )]
+    profile: String,
+    #[arg(
+        short,
+        long,
+        help = "Name advertised on the local network, the host name by default"
+    )]
+    name: Option<String>,
+    #[arg(long, help = "Don't advertise the server with mDNS")]
+    no_mdns: bool,
+}
+
+// Watch logs kept for clients that connect later
+const LOG_BACKLOG: usize = 500;
+// Devices clients may create, each one has its own threads
+const MAX_DEVICES: usize = 16;
+
+// One watch with its own serial thread
+pub struct Device {
+    tx_serial: Sender<SendToSerial>,
+    latest_frame: Option<(u16, u16, Vec<u8>)>,
+    recent_logs: VecDeque<String>,
+    // Sent to clients so they draw and log with the same firmware format
+    profile: Profile,
+    // Opened with --serial
+    fixed: bool,
+    // Last port asked for, no other device may open it
+    port: Option<String>,
+}
+
+impl Device {
+    pub fn spawn(
+        name: &str,
+        tx_main: Sender<ThreadCom>,
+        fixed: bool,
+        profile: &Profile,
+        rx_passthrough: Option<Receiver<serial::PassThrough>>,
+    ) -> Self {
+        let (tx_gui, rx_gui) = mpsc::channel();
+        let (tx_serial, rx_serial) = mpsc::channel();
+        thread::spawn(move || match rx_passthrough {
+            Some(rx_passthrough) => {
+                serial::main_with_passthrough(tx_gui, rx_serial, rx_passthrough)
+            }
+            None => serial::main(tx_gui, rx_serial),
+        });
+        // Serial output and network events are handled in order on the main thread
+        let name = name.to_string();
+        thread::spawn(move || {
+            for message in rx_gui {
+                if tx_main
+                    .send(ThreadCom::FromSerial(name.clone(), message))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        if *profile != Profile::default() {
+            let _ = tx_serial.send(SendToSerial::SetProfile(profile.clone()));
+        }
+        Self {
+            tx_serial,
+            latest_frame: None,
+            recent_logs: VecDeque::with_capacity(LOG_BACKLOG),
+            profile: profile.clone(),
+            fixed,
+            port: None,
+        }
+    }
+}
+
+// "name=path", a bare path is the default device. Paths may contain '=' too,
+// names never have a path separator
+pub fn parse_serial(arg: &str) -> Result<(String, String), String> {
+    let (name, path) = match arg.split_once('=') {
+        Some((name, path)) if !name.contains(['/', '\\']) => (name.to_string(), path.to_string()),
+        _ => (DEFAULT_DEVICE.to_string(), arg.to_string()),
+    };
+    discovery::check_device_name(&name)?;
+    if path.is_empty() {
+        return Err(format!("No path for device {}", name));
+    }
+    Ok((name, path))
+}
+
+// Every --serial, a name or a port can only be given once
+pub fn parse_serials(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut serials: Vec<(String, String)> = Vec::new();
+    for arg in args {
+        let (name, path) =
+            parse_serial(arg).map_err(|x| format!("Invalid --serial {}: {}", arg, x))?;
+        if serials.iter().any(|(other, _)| *other == name) {
+            return Err(format!(
+                "Device {} given twice, name them with --serial name=path",
+                name
+            ));
+        }
+        if let Some((other, _)) = serials.iter().find(|(_, other)| *other == path) {
+            return Err(format!("{} is given for {} and {}", path, other, name));
+        }
+        serials.push((name, path));
+    }
+    Ok(serials)
+}
+
+// What a connected client has already received
+#[derive(Default)]
+pub struct Client {
+    device: Option<String>,
+    last_frame: Option<Vec<u8>>,
+    since_keyframe: usize,
+}
+
+impl Client {
+    pub fn encode_frame(
+        &mut self,
+        width: u16,
+        height: u16,
+        frame: &[u8],
+        keyframe_interval: usize,
+    ) -> SendToGui {
+        let keyframe = match &self.last_frame {
+            Some(last) => last.len() != frame.len() || self.since_keyframe + 1 >= keyframe_interval,
+            None => true,
+        };
+        let message = if keyframe {
+            self.since_keyframe = 0;
+            SendToGui::ShowFrameRle(width, height, true, codec::rle_encode(frame))
+        } else {
+            self.since_keyframe += 1;
+            let delta = codec::xor(self.last_frame.as_ref().unwrap(), frame);
+            SendToGui::ShowFrameRle(width, height, false, codec::rle_encode(&delta))
+        };
+        self.last_frame = Some(frame.to_vec());
+        message
+    }
+}
+
+pub fn device_names(devices: &HashMap<String, Device>) -> Vec<String> {
+    let mut names: Vec<String> = devices.keys().cloned().collect();
+    names.sort();
+    names
+}
+
+fn advertise_devices(
+    advertiser: &Option<discovery::Advertiser>,
+    devices: &HashMap<String, Device>,
+) {
+    if let Some(advertiser) = advertiser {
+        if let Err(x) = advertiser.set_devices(&device_names(devices)) {
+            error!("{}", x);
+        }
+    }
+}
+
+// Devices created by clients go away with their last client, which closes the port
+fn remove_unwatched(
+    devices: &mut HashMap<String, Device>,
+    clients: &HashMap<Endpoint, Client>,
+    name: &str,
+) -> bool {
+    let watched = clients.values().any(|x| x.device.as_deref() == Some(name));
+    if watched || devices.get(name).is_none_or(|x| x.fixed) {
+        return false;
+    }
+    info!("Removing device {}, nobody watches it", name);
+    devices.remove(name);
+    true
 }
 
 pub fn send_network(
     network_handler: &NodeHandler<()>,
     endpoint: Option<Endpoint>,
+    device: &str,
     message: SendToGui,
 ) {
     if let Some(endpoint) = endpoint {
+        let message = Tagged {
+            device: device.to_string(),
+            message,
+        };
         let output_data = bincode::serialize(&message).unwrap();
         let status = network_handler.network().send(endpoint, &output_data);
         //debug!("Status of message {:?} is {:?}", message, status);
@@ -54,13 +268,65 @@ fn main() {
     debug!("Starting ws-serial-tcp");
 
     let args = Args::parse();
+    let profile = match Profile::find(&args.profile) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Failed to load profile: {}", x);
+            std::process::exit(2);
+        }
+    };
+    info!("Using profile {}", profile.name);
 
-    let mut endpoint_saved: Option<Endpoint> = None;
+    let mut clients: HashMap<Endpoint, Client> = HashMap::new();
+    let mut devices: HashMap<String, Device> = HashMap::new();
 
     // Threads
-    let (tx_gui, rx_gui) = mpsc::channel();
     let (tx_main, rx_main) = mpsc::channel();
-    let (tx_serial, rx_serial) = mpsc::channel();
+
+    // The server owns these devices from boot, parsing goes on without clients
+    let serials = match parse_serials(&args.serial) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("{}", x);
+            std::process::exit(2);
+        }
+    };
+    for (name, path) in serials {
+        info!(
+            "Opening {} as {} at {} {}",
+            path, name, args.baudrate, args.serial_config
+        );
+        let mut rx_passthrough = None;
+        if let Some(raw_port) = args.raw_port {
+            let (tx, rx) = mpsc::channel();
+            rx_passthrough = Some(rx);
+            let Some(raw_port) = u16::try_from(devices.len())
+                .ok()
+                .and_then(|x| raw_port.checked_add(x))
+            else {
+                error!(
+                    "No port left after {} for the pass-through of {}",
+                    raw_port, name
+                );
+                std::process::exit(2);
+            };
+            let name = name.clone();
+            let baud_rate = args.baudrate as u32;
+            thread::spawn(move || {
+                rfc2217::listen(raw_port, name, tx, baud_rate);
+            });
+        }
+        let mut device = Device::spawn(&name, tx_main.clone(), true, &profile, rx_passthrough);
+        device.port = Some(path.clone());
+        let _ = device.tx_serial.send(SendToSerial::OpenPort(
+            path,
+            args.baudrate,
+            args.serial_config.clone(),
+        ));
+        devices.insert(name, device);
+    }
+    // Without --serial clients create devices by naming them
+    let fixed_devices = !devices.is_empty();
 
     // Network
     let addr = ("0.0.0.0", args.port)
@@ -76,34 +342,320 @@ fn main() {
         Err(_) => error!("Can not listening at {} by {}", addr, transport),
     }
 
-    let network_handler_server = network_handler.clone();
-    thread::spawn(move || {
-        server::run(network_handler_server, listener, tx_serial, tx_main);
-    });
+    // Lets guis on the local network find the server and its watches
+    let advertiser = if args.no_mdns {
+        None
+    } else {
+        let name = args.name.clone().unwrap_or_else(discovery::host_name);
+        match discovery::Advertiser::new(&name, args.port, &device_names(&devices)) {
+            Ok(x) => Some(x),
+            Err(x) => {
+                error!("{}", x);
+                None
+            }
+        }
+    };
 
+    let network_handler_server = network_handler.clone();
+    let tx_main_server = tx_main.clone();
     thread::spawn(move || {
-        serial::main(tx_gui, rx_serial);
+        server::run(network_handler_server, listener, tx_main_server);
     });
 
-    loop {
-        match rx_gui.recv_timeout(Duration::from_millis(500)) {
-            Ok(x) => {
-                send_network(&network_handler.clone(), endpoint_saved, x);
+    for event in rx_main {
+        match event {
+            ThreadCom::FromSerial(name, SendToGui::ShowFrame(width, height, frame)) => {
+                for (endpoint, client) in clients.iter_mut() {
+                    if client.device.as_ref() != Some(&name) {
+                        continue;
+                    }
+                    let message =
+                        client.encode_frame(width, height, &frame, args.keyframe_interval);
+                    send_network(&network_handler, Some(*endpoint), &name, message);
+                }
+                if let Some(device) = devices.get_mut(&name) {
+                    device.latest_frame = Some((width, height, frame));
+                }
             }
-            Err(x) => {
-                if x != RecvTimeoutError::Timeout {
-                    error!("Failed to recv {}", x);
+            ThreadCom::FromSerial(name, x) => {
+                if let (SendToGui::LogToShow(logs), Some(device)) = (&x, devices.get_mut(&name)) {
+                    if device.recent_logs.len() == LOG_BACKLOG {
+                        device.recent_logs.pop_front();
+                    }
+                    device.recent_logs.push_back(logs.clone());
+                }
+                if let (SendToGui::Profile(profile), Some(device)) = (&x, devices.get_mut(&name)) {
+                    info!("Device {} uses profile {}", name, profile.name);
+                    device.profile = profile.clone();
+                }
+                if let SendToGui::Stats(stats) = &x {
+                    info!(
+                        "Link {}: {:.0} B/s, {:.1} frames/s, {:.1} ms decode latency, {} wrong length frames, {} resyncs",
+                        name,
+                        stats.bytes_per_sec,
+                        stats.frames_per_sec,
+                        stats.decode_latency_ms,
+                        stats.wrong_length_frames,
+                        stats.resyncs
+                    );
+                }
+                for (endpoint, client) in clients.iter() {
+                    if client.device.as_ref() == Some(&name) {
+                        send_network(&network_handler, Some(*endpoint), &name, x.clone());
+                    }
                 }
             }
-        }
-        // This will wait untill connection
-        if let Ok(event) = rx_main.try_recv() {
-            match event {
-                ThreadCom::ClientConnected(endpoint, _resource_id) => {
-                    info!("Server received: ClientConnected");
-                    endpoint_saved = Some(endpoint);
+            ThreadCom::FromClient(
+                endpoint,
+                Tagged {
+                    device: name,
+                    message,
+                },
+            ) => {
+                let Some(client) = clients.get_mut(&endpoint) else {
+                    continue;
+                };
+                if !devices.contains_key(&name) {
+                    if let SendToSerial::AskForDevices() = message {
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::Devices(device_names(&devices)),
+                        );
+                    }
+                    if fixed_devices || matches!(message, SendToSerial::AskForDevices()) {
+                        debug!("Client asked for unknown device {}", name);
+                        continue;
+                    }
+                    if let Err(x) = discovery::check_device_name(&name) {
+                        error!("Refusing to create device: {}", x);
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::LogToShow(format!(
+                                "Server refused to create device: {}\n",
+                                x
+                            )),
+                        );
+                        continue;
+                    }
+                    if devices.len() >= MAX_DEVICES {
+                        error!("Refusing to create device {}, too many devices", name);
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::LogToShow(format!(
+                                "Server refused to create {}, it hosts {} devices already\n",
+                                name, MAX_DEVICES
+                            )),
+                        );
+                        continue;
+                    }
+                    info!("Creating device {}", name);
+                    devices.insert(
+                        name.clone(),
+                        Device::spawn(&name, tx_main.clone(), false, &profile, None),
+                    );
+                    advertise_devices(&advertiser, &devices);
+                }
+                let taken_by = match &message {
+                    SendToSerial::SelectPort(path, _) | SendToSerial::OpenPort(path, _, _) => {
+                        devices
+                            .iter()
+                            .find(|(other, x)| **other != name && x.port.as_ref() == Some(path))
+                            .map(|(other, _)| other.clone())
+                    }
+                    _ => None,
+                };
+                let device = devices.get_mut(&name).unwrap();
+
+                let mut left = None;
+                if client.device.as_ref() != Some(&name) {
+                    info!("Client ({}) watches {}", endpoint.addr(), name);
+                    left = client.device.take();
+                    *client = Client {
+                        device: Some(name.clone()),
+                        ..Default::default()
+                    };
+                    send_network(
+                        &network_handler,
+                        Some(endpoint),
+                        &name,
+                        SendToGui::Profile(device.profile.clone()),
+                    );
+                    for logs in &device.recent_logs {
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::LogToShow(logs.clone()),
+                        );
+                    }
+                    // Clients start with a keyframe of what the watch shows now
+                    if let Some((width, height, frame)) = &device.latest_frame {
+                        let message =
+                            client.encode_frame(*width, *height, frame, args.keyframe_interval);
+                        send_network(&network_handler, Some(endpoint), &name, message);
+                    }
+                }
+
+                match message {
+                    // After the replay, clients know that what follows is new
+                    SendToSerial::AskForDevices() => {
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::Devices(device_names(&devices)),
+                        );
+                    }
+                    SendToSerial::SelectPort(..) | SendToSerial::OpenPort(..) if device.fixed => {
+                        debug!(
+                            "Ignoring {:?}, the port of {} was chosen on start",
+                            message, name
+                        );
+                    }
+                    SendToSerial::SelectPort(path, _) | SendToSerial::OpenPort(path, _, _)
+                        if taken_by.is_some() =>
+                    {
+                        let other = taken_by.unwrap();
+                        error!("Refusing {} for {}, {} uses it", path, name, other);
+                        send_network(
+                            &network_handler,
+                            Some(endpoint),
+                            &name,
+                            SendToGui::LogToShow(format!("{} is used by {}\n", path, other)),
+                        );
+                    }
+                    x => {
+                        if let SendToSerial::SelectPort(path, _)
+                        | SendToSerial::OpenPort(path, _, _) = &x
+                        {
+                            device.port = Some(path.clone());
+                        }
+                        if device.tx_serial.send(x).is_err() {
+                            error!("Failed to send to serial of {}", name);
+                        }
+                    }
+                }
+                if let Some(left) = left {
+                    if remove_unwatched(&mut devices, &clients, &left) {
+                        advertise_devices(&advertiser, &devices);
+                    }
+                }
+            }
+            ThreadCom::ClientConnected(endpoint, _resource_id) => {
+                info!("Server received: ClientConnected");
+                // Nothing is sent until the client names a device
+                clients.insert(endpoint, Client::default());
+            }
+            ThreadCom::ClientDisconnected(endpoint) => {
+                info!("Server received: ClientDisconnected");
+                if let Some(Client {
+                    device: Some(name), ..
+                }) = clients.remove(&endpoint)
+                {
+                    if remove_unwatched(&mut devices, &clients, &name) {
+                        advertise_devices(&advertiser, &devices);
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_keyframe(message: &SendToGui) -> bool {
+        matches!(message, SendToGui::ShowFrameRle(_, _, true, _))
+    }
+
+    #[test]
+    fn sends_keyframes_at_interval() {
+        let mut client = Client::default();
+        let frame = vec![0xFF; 8];
+        assert!(is_keyframe(&client.encode_frame(8, 8, &frame, 3)));
+        assert!(!is_keyframe(&client.encode_frame(8, 8, &frame, 3)));
+        assert!(!is_keyframe(&client.encode_frame(8, 8, &frame, 3)));
+        assert!(is_keyframe(&client.encode_frame(8, 8, &frame, 3)));
+    }
+
+    #[test]
+    fn sends_keyframe_on_size_change() {
+        let mut client = Client::default();
+        client.encode_frame(8, 8, &[0xFF; 8], 100);
+        let message = client.encode_frame(16, 8, &[0xFF; 16], 100);
+        assert!(is_keyframe(&message));
+    }
+
+    #[test]
+    fn deltas_hold_only_changes() {
+        let mut client = Client::default();
+        client.encode_frame(8, 8, &[0xFF; 8], 100);
+        let mut frame = vec![0xFF; 8];
+        frame[3] = 0x00;
+        let SendToGui::ShowFrameRle(8, 8, false, data) = client.encode_frame(8, 8, &frame, 100)
+        else {
+            panic!("Expected a delta");
+        };
+        assert_eq!(data, [3, 0, 1, 0xFF, 4, 0]);
+    }
+
+    fn serials(args: &[&str]) -> Result<Vec<(String, String)>, String> {
+        parse_serials(&args.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+    }
+
+    fn pair(name: &str, path: &str) -> (String, String) {
+        (name.to_string(), path.to_string())
+    }
+
+    #[test]
+    fn parses_serial_specs() {
+        assert_eq!(
+            parse_serial("/dev/ttyUSB0"),
+            Ok(pair(DEFAULT_DEVICE, "/dev/ttyUSB0"))
+        );
+        assert_eq!(
+            parse_serial("desk=/dev/ttyUSB0"),
+            Ok(pair("desk", "/dev/ttyUSB0"))
+        );
+        assert_eq!(parse_serial("COM3"), Ok(pair(DEFAULT_DEVICE, "COM3")));
+        // Only the first '=' separates the name
+        assert_eq!(
+            parse_serial("desk=/dev/by-id/a=b"),
+            Ok(pair("desk", "/dev/by-id/a=b"))
+        );
+        // A bare path with '=' in it
+        assert_eq!(
+            parse_serial("/dev/by-id/a=b"),
+            Ok(pair(DEFAULT_DEVICE, "/dev/by-id/a=b"))
+        );
+    }
+
+    #[test]
+    fn refuses_bad_serial_specs() {
+        assert!(parse_serial("=/dev/ttyUSB0").is_err());
+        assert!(parse_serial("desk=").is_err());
+        assert!(parse_serial("").is_err());
+        assert!(parse_serial("a,b=/dev/ttyUSB0").is_err());
+    }
+
+    #[test]
+    fn refuses_duplicate_serials() {
+        assert_eq!(
+            serials(&["left=/dev/ttyUSB0", "right=/dev/ttyUSB1"]),
+            Ok(vec![
+                pair("left", "/dev/ttyUSB0"),
+                pair("right", "/dev/ttyUSB1")
+            ])
+        );
+        assert!(serials(&["/dev/ttyUSB0", "/dev/ttyUSB1"]).is_err());
+        assert!(serials(&["left=/dev/ttyUSB0", "left=/dev/ttyUSB1"]).is_err());
+        assert!(serials(&["left=/dev/ttyUSB0", "right=/dev/ttyUSB0"]).is_err());
+    }
+}
@@ -0,0 +1,476 @@
+// Raw serial access over telnet with the RFC 2217 com port option, enough for
+// esptool (rfc2217://host:port) and idf.py monitor. Plain TCP clients work too,
+// as long as they don't send 0xFF bytes.
+
+// Logging
+use log::{debug, error, info};
+
+// Network
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Threads
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Other
+use crate::serial::PassThrough;
+use serialport::{DataBits, Parity, StopBits};
+
+// Telnet
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SGA: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// Client to server com port commands, the server answers with the command + 100
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+enum State {
+    Data,
+    Iac,
+    // WILL, WONT, DO or DONT waiting for the option
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+struct Session {
+    tx_passthrough: Sender<PassThrough>,
+    state: State,
+    sub: Vec<u8>,
+    // Current settings, sent back on queries
+    baud_rate: u32,
+    data_size: u8,
+    parity: u8,
+    stop_size: u8,
+}
+
+impl Session {
+    fn new(tx_passthrough: Sender<PassThrough>, baud_rate: u32) -> Self {
+        Self {
+            tx_passthrough,
+            state: State::Data,
+            sub: Vec::new(),
+            baud_rate,
+            data_size: 8,
+            parity: 1,
+            stop_size: 1,
+        }
+    }
+
+    fn send(&self, request: PassThrough) {
+        if self.tx_passthrough.send(request).is_err() {
+            error!("Failed to send pass-through request to serial");
+        }
+    }
+
+    // Splits telnet commands from the data, returns the answers for the client
+    fn feed(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(input.len());
+        let mut reply = Vec::new();
+        for &byte in input {
+            self.state = match self.state {
+                State::Data if byte == IAC => State::Iac,
+                State::Data => {
+                    data.push(byte);
+                    State::Data
+                }
+                State::Iac => match byte {
+                    IAC => {
+                        data.push(IAC);
+                        State::Data
+                    }
+                    WILL | WONT | DO | DONT => State::Option(byte),
+                    SB => {
+                        self.sub.clear();
+                        State::Sub
+                    }
+                    _ => State::Data,
+                },
+                State::Option(command) => {
+                    let supported = matches!(byte, BINARY | SGA | COM_PORT_OPTION);
+                    // Only answer requests, we never ask for anything ourselves
+                    match command {
+                        WILL => reply.extend([IAC, if supported { DO } else { DONT }, byte]),
+                        DO => reply.extend([IAC, if supported { WILL } else { WONT }, byte]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                State::Sub if byte == IAC => State::SubIac,
+                State::Sub => {
+                    self.sub.push(byte);
+                    State::Sub
+                }
+                State::SubIac if byte == SE => {
+                    let sub = std::mem::take(&mut self.sub);
+                    self.subnegotiation(&sub, &mut reply);
+                    State::Data
+                }
+                State::SubIac => {
+                    self.sub.push(byte);
+                    State::Sub
+                }
+            };
+        }
+        if !data.is_empty() {
+            self.send(PassThrough::Write(data));
+        }
+        reply
+    }
+
+    fn subnegotiation(&mut self, sub: &[u8], reply: &mut Vec<u8>) {
+        let [COM_PORT_OPTION, command, value @ ..] = sub else {
+            debug!("Ignoring subnegotiation {:?}", sub);
+            return;
+        };
+        // PURGE_DATA is the last client command, the answer wouldn't fit in a byte
+        if *command > PURGE_DATA {
+            debug!("Ignoring com port command {}", command);
+            return;
+        }
+        let first = value.first().copied().unwrap_or(0);
+        let answer: Vec<u8> = match *command {
+            SET_BAUDRATE => {
+                if let Ok(bytes) = <[u8; 4]>::try_from(value) {
+                    let baud_rate = u32::from_be_bytes(bytes);
+                    // 0 only asks for the current value
+                    if baud_rate != 0 {
+                        debug!("Pass-through baud rate {}", baud_rate);
+                        self.baud_rate = baud_rate;
+                        self.send(PassThrough::SetBaudRate(baud_rate));
+                    }
+                }
+                self.baud_rate.to_be_bytes().to_vec()
+            }
+            SET_DATASIZE => {
+                let data_bits = match first {
+                    5 => Some(DataBits::Five),
+                    6 => Some(DataBits::Six),
+                    7 => Some(DataBits::Seven),
+                    8 => Some(DataBits::Eight),
+                    _ => None,
+                };
+                if let Some(data_bits) = data_bits {
+                    self.data_size = first;
+                    self.send(PassThrough::SetDataBits(data_bits));
+                }
+                vec![self.data_size]
+            }
+            SET_PARITY => {
+                let parity = match first {
+                    1 => Some(Parity::None),
+                    2 => Some(Parity::Odd),
+                    3 => Some(Parity::Even),
+                    _ => None,
+                };
+                if let Some(parity) = parity {
+                    self.parity = first;
+                    self.send(PassThrough::SetParity(parity));
+                }
+                vec![self.parity]
+            }
+            SET_STOPSIZE => {
+                let stop_bits = match first {
+                    1 => Some(StopBits::One),
+                    2 => Some(StopBits::Two),
+                    _ => None,
+                };
+                if let Some(stop_bits) = stop_bits {
+                    self.stop_size = first;
+                    self.send(PassThrough::SetStopBits(stop_bits));
+                }
+                vec![self.stop_size]
+            }
+            SET_CONTROL => {
+                // esptool resets the chip into the bootloader with these
+                match first {
+                    8 => self.send(PassThrough::SetDtr(true)),
+                    9 => self.send(PassThrough::SetDtr(false)),
+                    11 => self.send(PassThrough::SetRts(true)),
+                    12 => self.send(PassThrough::SetRts(false)),
+                    _ => {}
+                }
+                vec![first]
+            }
+            PURGE_DATA => {
+                self.send(PassThrough::Purge);
+                vec![first]
+            }
+            // Masks and flow control suspend are acknowledged as they are
+            _ => value.to_vec(),
+        };
+        reply.extend([IAC, SB, COM_PORT_OPTION, command + SERVER_OFFSET]);
+        reply.extend(escape(&answer));
+        reply.extend([IAC, SE]);
+    }
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data {
+        out.push(byte);
+        if byte == IAC {
+            out.push(IAC);
+        }
+    }
+    out
+}
+
+// Ends the pass-through however the session ends, the serial thread would
+// stay paused otherwise
+struct StopOnDrop(Sender<PassThrough>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.send(PassThrough::Stop);
+    }
+}
+
+fn handle_client(stream: TcpStream, tx_passthrough: Sender<PassThrough>, baud_rate: u32) {
+    let _ = stream.set_nodelay(true);
+    let Ok(mut reader) = stream.try_clone() else {
+        error!("Failed to clone pass-through stream");
+        return;
+    };
+    // Port data and telnet answers share the socket
+    let writer = Arc::new(Mutex::new(stream));
+
+    let (tx_data, rx_data) = mpsc::channel();
+    if tx_passthrough.send(PassThrough::Start(tx_data)).is_err() {
+        error!("Failed to start pass-through");
+        return;
+    }
+    let _stop = StopOnDrop(tx_passthrough.clone());
+    let writer_serial = writer.clone();
+    thread::spawn(move || {
+        for data in rx_data {
+            if writer_serial
+                .lock()
+                .unwrap()
+                .write_all(&escape(&data))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(tx_passthrough.clone(), baud_rate);
+    let mut buf = vec![0; 4096];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(readed) => {
+                let reply = session.feed(&buf[..readed]);
+                if !reply.is_empty() && writer.lock().unwrap().write_all(&reply).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    // Stopping drops the data sender in the serial thread, which ends the writer
+}
+
+// Serves one client at a time, the next one waits until the port is free
+pub fn listen(port: u16, name: String, tx_passthrough: Sender<PassThrough>, baud_rate: u32) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(x) => x,
+        Err(x) => {
+            error!(
+                "Can not listen for pass-through of {} at {}: {}",
+                name, port, x
+            );
+            return;
+        }
+    };
+    info!("Pass-through of {} at rfc2217://0.0.0.0:{}", name, port);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let peer = stream
+                    .peer_addr()
+                    .map(|x| x.to_string())
+                    .unwrap_or_default();
+                info!("Pass-through client ({}) connected to {}", peer, name);
+                handle_client(stream, tx_passthrough.clone(), baud_rate);
+                info!("Pass-through client ({}) disconnected from {}", peer, name);
+            }
+            Err(x) => error!("Failed to accept pass-through client: {}", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+
+    fn session() -> (Session, Receiver<PassThrough>) {
+        let (tx, rx) = mpsc::channel();
+        (Session::new(tx, 115200), rx)
+    }
+
+    #[test]
+    fn unescapes_iac_in_data() {
+        let (mut session, rx) = session();
+        assert!(session.feed(&[1, IAC, IAC, 2]).is_empty());
+        // Split between reads
+        assert!(session.feed(&[IAC]).is_empty());
+        assert!(session.feed(&[IAC, 3]).is_empty());
+        let written: Vec<Vec<u8>> = rx
+            .try_iter()
+            .map(|x| match x {
+                PassThrough::Write(x) => x,
+                _ => panic!("Expected data"),
+            })
+            .collect();
+        assert_eq!(written, [vec![1, IAC, 2], vec![IAC, 3]]);
+    }
+
+    #[test]
+    fn answers_option_requests() {
+        let (mut session, rx) = session();
+        let reply = session.feed(&[IAC, DO, COM_PORT_OPTION, IAC, WILL, BINARY, IAC, DO, 99]);
+        assert_eq!(
+            reply,
+            [IAC, WILL, COM_PORT_OPTION, IAC, DO, BINARY, IAC, WONT, 99]
+        );
+        assert!(session.feed(&[IAC, WONT, SGA, IAC, DONT, SGA]).is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn sets_baud_rate_in_subnegotiation() {
+        let (mut session, rx) = session();
+        // 0x0001C2FF has an IAC, doubled on the wire both ways
+        let request = [
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            SET_BAUDRATE,
+            0x00,
+            0x01,
+            0xC2,
+            IAC,
+            IAC,
+            IAC,
+            SE,
+        ];
+        assert!(session.feed(&request[..5]).is_empty());
+        let reply = session.feed(&request[5..]);
+        assert_eq!(
+            reply,
+            [
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_BAUDRATE + SERVER_OFFSET,
+                0x00,
+                0x01,
+                0xC2,
+                IAC,
+                IAC,
+                IAC,
+                SE
+            ]
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(PassThrough::SetBaudRate(0x0001C2FF))
+        ));
+    }
+
+    #[test]
+    fn answers_queries_with_current_settings() {
+        let (mut session, rx) = session();
+        let reply = session.feed(&[IAC, SB, COM_PORT_OPTION, SET_BAUDRATE, 0, 0, 0, 0, IAC, SE]);
+        let mut expected = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE + SERVER_OFFSET];
+        expected.extend(115200u32.to_be_bytes());
+        expected.extend([IAC, SE]);
+        assert_eq!(reply, expected);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn forwards_control_lines() {
+        let (mut session, rx) = session();
+        let reply = session.feed(&[
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            SET_CONTROL,
+            8,
+            IAC,
+            SE,
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            SET_CONTROL,
+            12,
+            IAC,
+            SE,
+        ]);
+        assert_eq!(
+            reply,
+            [
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_CONTROL + SERVER_OFFSET,
+                8,
+                IAC,
+                SE,
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_CONTROL + SERVER_OFFSET,
+                12,
+                IAC,
+                SE
+            ]
+        );
+        assert!(matches!(rx.try_recv(), Ok(PassThrough::SetDtr(true))));
+        assert!(matches!(rx.try_recv(), Ok(PassThrough::SetRts(false))));
+    }
+
+    #[test]
+    fn ignores_unknown_com_port_commands() {
+        let (mut session, rx) = session();
+        for command in [13, 155, 156, 255] {
+            let mut request = vec![IAC, SB, COM_PORT_OPTION];
+            request.extend(escape(&[command, 1]));
+            request.extend([IAC, SE]);
+            assert!(session.feed(&request).is_empty());
+        }
+        assert!(rx.try_recv().is_err());
+        // The session goes on
+        let reply = session.feed(&[IAC, SB, COM_PORT_OPTION, SET_DATASIZE, 0, IAC, SE]);
+        assert_eq!(reply, [IAC, SB, COM_PORT_OPTION, 102, 8, IAC, SE]);
+    }
+
+    #[test]
+    fn stops_pass_through_on_drop() {
+        let (tx, rx) = mpsc::channel();
+        let result = std::panic::catch_unwind(move || {
+            let _stop = StopOnDrop(tx);
+            panic!("Session failed");
+        });
+        assert!(result.is_err());
+        assert!(matches!(rx.try_recv(), Ok(PassThrough::Stop)));
+    }
+}
@@ -10,7 +10,7 @@ use message_io::node::{NodeHandler, NodeListener};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 
-pub fn run(_handler: Arc<NodeHandler<()>>, listener: NodeListener<()>, tx_to_serial: Sender<SendToSerial>, tx_to_main: Sender<ThreadCom>) {
+pub fn run(_handler: Arc<NodeHandler<()>>, listener: NodeListener<()>, tx_to_main: Sender<ThreadCom>) {
     listener.for_each(move |event| match event.network() {
         NetEvent::Connected(_, _) => (),
         NetEvent::Accepted(endpoint, _listener_id) => {
@@ -18,15 +18,23 @@ pub fn run(_handler: Arc<NodeHandler<()>>, listener: NodeListener<()>, tx_to_ser
             info!("Client ({}) connected", endpoint.addr());
             let _ = tx_to_main.send(ThreadCom::ClientConnected(endpoint, _listener_id));
         }
-        NetEvent::Message(_endpoint, input_data) => {
+        NetEvent::Message(endpoint, input_data) => {
             debug!("Received raw input data with length: {}", input_data.len());
-            let message: SendToSerial = bincode::deserialize(input_data).unwrap();
-            if tx_to_serial.send(message).is_err() {
-                error!("Failed to send to serial");
+            let message: Tagged<SendToSerial> = match bincode::deserialize(input_data) {
+                Ok(x) => x,
+                Err(x) => {
+                    error!("Failed to decode message from {}: {}", endpoint.addr(), x);
+                    return;
+                }
+            };
+            // Routed to the device by the main thread
+            if tx_to_main.send(ThreadCom::FromClient(endpoint, message)).is_err() {
+                error!("Failed to send to main");
             }
         }
         NetEvent::Disconnected(endpoint) => {
             info!("Client ({}) disconnected", endpoint.addr(),);
+            let _ = tx_to_main.send(ThreadCom::ClientDisconnected(endpoint));
         }
     });
 }
\ No newline at end of file